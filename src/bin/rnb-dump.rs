@@ -1,95 +1,171 @@
-use std::{char::decode_utf16, env::args_os, fs};
+use rnb::format::{BlockRef, Reader, SectionKind};
+use std::{env::args_os, fs, path::PathBuf, process::ExitCode};
+
+fn main() -> ExitCode {
+    let Some(path) = args_os().nth(1).map(PathBuf::from) else {
+        eprintln!("usage: rnb-dump path/to/file.rnb");
+        return ExitCode::from(2);
+    };
+
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    };
 
-fn main() {
-    let path = args_os().nth(1).unwrap();
+    let reader = match Reader::new(&bytes) {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("{}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let bytes = fs::read(path).unwrap();
-    let mut bytes = bytes.as_slice();
+    dump_header(&reader);
+    dump_metadata(&reader);
+    dump_toc(&reader);
+    dump_images(&reader);
+    dump_blocks(&reader);
 
-    let num_blocks = u16::from_le_bytes([bytes[0], bytes[1]]);
-    bytes = &bytes[2..];
-    println!("num blocks {num_blocks}");
+    ExitCode::SUCCESS
+}
 
-    let num_images = bytes[0];
-    bytes = &bytes[1..];
-    println!("num images {num_images}");
+fn dump_header(reader: &Reader<'_>) {
+    let Some(header) = reader.header() else {
+        println!("legacy format without a header");
+        return;
+    };
+
+    println!(
+        "version {}, flags={:#06x}, num sections {}",
+        header.version,
+        header.flags,
+        header.sections.len(),
+    );
+
+    for section in &header.sections {
+        let kind = match SectionKind::try_from(section.kind) {
+            Ok(kind) => format!("{kind:?}"),
+            Err(kind) => format!("unknown ({kind})"),
+        };
+        println!(
+            "section: kind={kind}, offset={}, length={}",
+            section.offset, section.length,
+        );
+    }
+}
 
-    for i in 0..num_images {
-        let offset = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
-        bytes = &bytes[4..];
+fn dump_metadata(reader: &Reader<'_>) {
+    let metadata = reader.metadata();
 
-        let uncompressed_length = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
-        bytes = &bytes[4..];
+    println!("title {}", metadata.title);
+    println!("language {}", metadata.language);
+    println!("publisher {}", metadata.publisher);
+    println!("identifier {}", metadata.identifier);
+    println!("date {}", metadata.date);
 
-        println!("image meta {i}: offset={offset}, uncompressed_length={uncompressed_length}");
+    for (i, creator) in metadata.creators.iter().enumerate() {
+        println!("creator: idx={i}, role={}", creator.role);
+        println!("{}", creator.name);
     }
 
-    for i in 0..num_blocks {
-        let prefix = u16::from_le_bytes([bytes[0], bytes[1]]);
-        bytes = &bytes[2..];
+    if let Some(series) = &metadata.series {
+        println!("series {}, index={}", series.name, series.index);
+    }
 
-        let is_image = (prefix & (1 << 15)) != 0;
-        let is_bold = (prefix & (1 << 14)) != 0;
-        let is_large = (prefix & (1 << 13)) != 0;
+    if let Some(index) = metadata.cover_image {
+        println!("cover image idx={index}");
+    }
+}
 
-        if is_image {
-            println!("image {}", prefix & !(1 << 15));
-            continue;
-        }
+fn dump_toc(reader: &Reader<'_>) {
+    println!("num toc entries {}", reader.toc().len());
 
-        let length = prefix & !(0b111 << 13);
-        if length == 0 {
+    for (i, entry) in reader.toc().enumerate() {
+        println!(
+            "toc entry: idx={i}, block_idx={}, level={}",
+            entry.block_idx, entry.level,
+        );
+        println!("{}", entry.title);
+    }
+}
+
+fn dump_images(reader: &Reader<'_>) {
+    println!("num images {}", reader.num_images());
+
+    for (i, image) in reader.images().enumerate() {
+        let info = reader.image_info(i);
+        println!(
+            "image meta {i}: offset={}, length={}, format={:?}, width={}, height={}",
+            reader.image_offset(i).unwrap_or_default(),
+            image.len(),
+            info.format,
+            info.width,
+            info.height,
+        );
+    }
+}
+
+fn dump_blocks(reader: &Reader<'_>) {
+    println!("num blocks {}", reader.num_blocks());
+
+    for (i, block) in reader.blocks().enumerate() {
+        let block = match block {
+            BlockRef::Text(block) => block,
+            BlockRef::Image { index } => {
+                println!("image {index}");
+                continue;
+            }
+        };
+
+        let is_bold = block.flags & (1 << 0) != 0;
+        let is_large = block.flags & (1 << 1) != 0;
+        let continues = block.flags & (1 << 2) != 0;
+        let layout = block.flags & !0b111;
+        let heading = block.heading_level;
+
+        if block.text.is_empty() {
             println!(
-                "zero length block: idx={i}, bold={is_bold}, is_large={is_large}, prefix={prefix}",
+                "zero length block: idx={i}, bold={is_bold}, is_large={is_large}, continues={continues}, layout={layout:#04x}, heading={heading}"
             );
             continue;
         }
 
-        assert!(length % 2 == 0, "{length}");
-
-        let text = &bytes[..usize::from(length)];
-        bytes = &bytes[usize::from(length)..];
-        let text = decode_utf16(
-            text.chunks_exact(2)
-                .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]])),
-        )
-        .map(|ch| ch.unwrap())
-        .collect::<String>();
+        println!(
+            "text block meta: idx={i}, bold={is_bold}, is_large={is_large}, continues={continues}, layout={layout:#04x}, heading={heading}"
+        );
+        println!("{}", block.text);
 
-        println!("text block meta: idx={i}, bold={is_bold}, is_large={is_large}");
-        println!("{text}");
-
-        let num_ruby = bytes[0];
-        bytes = &bytes[1..];
-        if num_ruby == 0 {
-            continue;
+        for (j, r) in block.ruby().enumerate() {
+            println!(
+                "ruby meta: idx={j}, start_offset={}, num_chars_in_text={}",
+                r.start_offset, r.length,
+            );
+            println!("{}", r.reading);
         }
 
-        for j in 0..num_ruby {
-            let start_offset = u16::from_le_bytes([bytes[0], bytes[1]]);
-            bytes = &bytes[2..];
-
-            let num_chars_in_text = bytes[0];
-            bytes = &bytes[1..];
-
-            let reading_len = bytes[0];
-            assert!(reading_len % 2 == 0, "{reading_len}");
-            bytes = &bytes[1..];
+        for (j, r) in block.secondary_ruby().enumerate() {
+            println!(
+                "secondary ruby meta: idx={j}, start_offset={}, num_chars_in_text={}",
+                r.start_offset, r.length,
+            );
+            println!("{}", r.reading);
+        }
 
-            let reading = &bytes[..usize::from(reading_len)];
-            bytes = &bytes[usize::from(reading_len)..];
-            let reading = decode_utf16(
-                reading
-                    .chunks_exact(2)
-                    .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]])),
-            )
-            .map(|ch| ch.unwrap())
-            .collect::<String>();
+        for (j, span) in block.spans().enumerate() {
+            println!(
+                "span meta: idx={j}, start_offset={}, num_chars_in_text={}, style={:?}",
+                span.start_offset, span.length, span.style,
+            );
+        }
 
+        for (j, emphasis) in block.emphasis().enumerate() {
             println!(
-                "ruby meta: idx={j}, start_offset={start_offset}, num_chars_in_text={num_chars_in_text}"
+                "emphasis meta: idx={j}, start_offset={}, num_chars_in_text={}, style={:?}",
+                emphasis.start_offset, emphasis.length, emphasis.style,
             );
-            println!("{reading}");
         }
     }
 }
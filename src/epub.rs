@@ -0,0 +1,916 @@
+//! Conversion of EPUB books into a [`Book`].
+
+mod image;
+mod package;
+mod profile;
+mod reencode;
+mod style;
+mod text;
+mod toc;
+
+use crate::{Book, ContentBlock, Image, ImageFormat, TocEntry};
+use image::{detect_format, dimensions, format_of_media_type};
+use package::{Package, parent_dir, parse_package};
+use quick_xml::{Reader, escape::resolve_html5_entity, events::attributes::Attributes};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use reencode::reencode;
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+    str,
+};
+use style::{Stylesheet, parse_stylesheet};
+use text::{ParsedText, flatten_text, merge_paragraphs, parse_text_file};
+use toc::{TocItem, parse_nav, parse_ncx};
+use zip::{ZipArchive, result::ZipError};
+
+pub use profile::{Profile, ProfileError, ProfileErrorKind};
+pub use reencode::{DEFAULT_JPEG_QUALITY, ImageOptions};
+pub use text::{ErrorMode, TextError, TextErrorKind};
+
+type Archive = ZipArchive<File>;
+
+/// Options control which content of the EPUB is converted, and how problems are handled.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// include_non_linear controls whether spine items marked with `linear="no"` are included in
+    /// the output.
+    pub include_non_linear: bool,
+    pub error_mode: ErrorMode,
+    pub images: ImageOptions,
+    /// profile describes the class names of the publisher of the book.
+    pub profile: Profile,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            include_non_linear: true,
+            error_mode: ErrorMode::Strict,
+            images: ImageOptions::default(),
+            profile: Profile::default(),
+        }
+    }
+}
+
+/// Conversion is the result of successfully converting a book.
+#[derive(Debug)]
+pub struct Conversion {
+    pub book: Book,
+    /// warnings are the problems which were worked around in [`ErrorMode::Lenient`].
+    pub warnings: Vec<Warning>,
+    pub image_savings: ImageSavings,
+}
+
+/// ImageSavings describes the images of the EPUB which were left out of the book or made smaller.
+/// Images which no block refers to are left out, identical images are stored once, and images are
+/// re-encoded according to [`ImageOptions`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImageSavings {
+    pub unreferenced: usize,
+    /// unreferenced_bytes is the total size of the unreferenced images.
+    pub unreferenced_bytes: u64,
+    /// duplicates is the number of images which are identical to an image that was stored.
+    pub duplicates: usize,
+    /// duplicate_bytes is the total size of the duplicates.
+    pub duplicate_bytes: u64,
+    /// reencoded is the number of images which were re-encoded.
+    pub reencoded: usize,
+    /// reencoded_bytes is the number of bytes that re-encoding saved. It can be negative, since
+    /// images which are too large to show are scaled down even when that makes them larger.
+    pub reencoded_bytes: i64,
+}
+
+struct TextFiles {
+    /// paths are the paths of the text files within the archive, in reading order.
+    paths: Box<[String]>,
+    file_numbers: Box<[usize]>,
+}
+
+#[derive(Debug, Default)]
+struct ImageFiles {
+    /// indices maps the path within the archive of each image to its index.
+    indices: HashMap<String, u32>,
+    /// media_type_formats are the formats of the images according to their media types.
+    media_type_formats: Box<[ImageFormat]>,
+    uncompressed_lengths: Box<[u64]>,
+    file_numbers: Box<[usize]>,
+}
+
+impl ImageFiles {
+    /// index_of returns the index of the image at `path` within the archive.
+    fn index_of(&self, path: &str) -> Option<u32> {
+        self.indices.get(path).copied()
+    }
+}
+
+/// Gaiji are the replacements for gaiji from `gaiji.json`, keyed by either the path of the image
+/// within the archive or its file name.
+#[derive(Debug, Default)]
+struct Gaiji {
+    replacements: HashMap<String, Box<[u16]>>,
+}
+
+impl Gaiji {
+    /// mapped returns the replacement for the gaiji at `path` within the archive, preferring one
+    /// for the full path over one for the file name. It's empty when there isn't one.
+    fn mapped(&self, path: &str) -> &[u16] {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+
+        self.replacements
+            .get(path)
+            .or_else(|| self.replacements.get(file_name))
+            .map(AsRef::as_ref)
+            .unwrap_or_default()
+    }
+}
+
+/// Stylesheets are the CSS files in the manifest, keyed by their path within the archive.
+#[derive(Debug, Default)]
+struct Stylesheets {
+    by_path: HashMap<String, Stylesheet>,
+}
+
+impl Stylesheets {
+    /// get returns the stylesheet at `path` within the archive.
+    fn get(&self, path: &str) -> Option<&Stylesheet> {
+        self.by_path.get(path)
+    }
+}
+
+/// ConvertError is a reason that a book couldn't be converted.
+#[derive(Debug)]
+pub enum ConvertError {
+    /// A file outside of the archive couldn't be read or written.
+    Io { path: PathBuf, source: io::Error },
+    /// The input isn't a valid zip archive.
+    Zip(ZipError),
+    /// A file that the book refers to isn't in the archive.
+    MissingFile { path: String },
+    /// A file in the archive couldn't be read.
+    ReadEntry { path: String, source: io::Error },
+    /// `META-INF/container.xml` doesn't point to a package document.
+    MissingRootFile,
+    /// An XML document in the book, other than a text file, is malformed.
+    Xml { path: String, error: XmlError },
+    /// A text file contains something that can't be converted.
+    Text { path: String, error: TextError },
+    /// The book has content which is too large to be encoded in the file format. The string
+    /// describes what's too large.
+    TooLarge(String),
+}
+
+impl ConvertError {
+    fn from_zip(path: &str, error: ZipError) -> Self {
+        match error {
+            ZipError::FileNotFound => Self::MissingFile {
+                path: path.to_string(),
+            },
+            _ => Self::Zip(error),
+        }
+    }
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Self::Zip(error) => write!(f, "invalid archive: {error}"),
+            Self::MissingFile { path } => write!(f, "missing {path}"),
+            Self::ReadEntry { path, source } => write!(f, "couldn't read {path}: {source}"),
+            Self::MissingRootFile => {
+                write!(
+                    f,
+                    "META-INF/container.xml doesn't have a rootfile full-path"
+                )
+            }
+            Self::Xml { path, error } => {
+                write!(f, "{path} (byte {}): {}", error.position, error.source)
+            }
+            Self::Text { path, error } => write!(f, "{path}:{error}"),
+            Self::TooLarge(what) => write!(f, "too large to encode: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+/// XmlError is an error from parsing an XML document, along with the byte offset where it
+/// occurred.
+#[derive(Debug)]
+pub struct XmlError {
+    pub position: u64,
+    pub source: quick_xml::Error,
+}
+
+impl XmlError {
+    fn at<R>(reader: &Reader<R>, source: impl Into<quick_xml::Error>) -> Self {
+        Self {
+            position: reader.buffer_position(),
+            source: source.into(),
+        }
+    }
+
+    /// read creates an error for a failure to read the next event from `reader`.
+    fn read<R>(reader: &Reader<R>, source: quick_xml::Error) -> Self {
+        Self {
+            position: reader.error_position(),
+            source,
+        }
+    }
+}
+
+/// Warning is a problem in a text file which was worked around instead of stopping the
+/// conversion.
+#[derive(Debug)]
+pub struct Warning {
+    /// path is the path within the archive of the text file.
+    pub path: String,
+    pub error: TextError,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path, self.error)
+    }
+}
+
+/// convert reads the EPUB at `path`.
+pub fn convert(path: &Path, options: &Options) -> Result<Conversion, ConvertError> {
+    let mut z: Archive = ZipArchive::new(open(path)?).map_err(ConvertError::Zip)?;
+
+    let mut package = get_package(&mut z)?;
+    let text_files = get_text_files(&mut z, &package, options.include_non_linear)?;
+    let image_files = get_image_files(&mut z, &package)?;
+    let gaiji = get_gaiji(&mut z)?;
+    let stylesheets = get_stylesheets(&mut z, &package)?;
+    let toc = get_toc(&mut z, &package)?;
+
+    let mut text = parse_paragraphs(
+        path,
+        &text_files,
+        &image_files,
+        gaiji,
+        &stylesheets,
+        &options.profile,
+        options.error_mode,
+    )?;
+    let warnings = text_files
+        .paths
+        .iter()
+        .zip(&mut text)
+        .flat_map(|(path, t)| {
+            t.warnings.drain(..).map(|error| Warning {
+                path: path.clone(),
+                error,
+            })
+        })
+        .collect::<Vec<_>>();
+    let (paragraphs, toc_paragraphs) = flatten_text(text, &text_files.paths, &toc);
+    let (mut blocks, paragraph_blocks) = merge_paragraphs(paragraphs);
+
+    let toc = toc
+        .into_iter()
+        .zip(toc_paragraphs)
+        .filter_map(|(item, paragraph_idx)| {
+            let block_idx = *paragraph_blocks.get(paragraph_idx?)?;
+
+            Some(TocEntry {
+                title: item.title,
+                level: item.level,
+                block_idx: block_idx.try_into().ok()?,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let cover = package
+        .cover_href()
+        .and_then(|href| image_files.index_of(&package.path_of(href)));
+    let (images, image_savings, cover) =
+        embed_images(path, &image_files, cover, &options.images, &mut blocks)?;
+    package.metadata.cover_image = cover;
+
+    Ok(Conversion {
+        book: Book {
+            metadata: package.metadata,
+            toc,
+            blocks,
+            images,
+        },
+        warnings,
+        image_savings,
+    })
+}
+
+fn open(path: &Path) -> Result<File, ConvertError> {
+    File::open(path).map_err(|source| ConvertError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn get_text_files(
+    z: &mut Archive,
+    package: &Package,
+    include_non_linear: bool,
+) -> Result<TextFiles, ConvertError> {
+    let paths = package
+        .text_hrefs(include_non_linear)
+        .map(|href| package.path_of(href))
+        .collect::<Vec<_>>();
+    let mut contents = Vec::with_capacity(paths.len());
+
+    for p in &paths {
+        let num = z
+            .index_for_name(p)
+            .ok_or_else(|| ConvertError::MissingFile { path: p.clone() })?;
+        contents.push(num);
+    }
+
+    Ok(TextFiles {
+        paths: paths.into_boxed_slice(),
+        file_numbers: contents.into_boxed_slice(),
+    })
+}
+
+fn get_package(z: &mut Archive) -> Result<Package, ConvertError> {
+    let root_file_path = get_root_file_path(z)?;
+
+    let root_file = z
+        .by_name(&root_file_path)
+        .map_err(|e| ConvertError::from_zip(&root_file_path, e))?;
+    let root_file = BufReader::with_capacity(16 * 1024, root_file);
+
+    let mut package = parse_package(root_file).map_err(|error| ConvertError::Xml {
+        path: root_file_path.clone(),
+        error,
+    })?;
+    package.dir = parent_dir(&root_file_path).to_string();
+
+    Ok(package)
+}
+
+/// get_toc reads the table of contents from the EPUB 3 navigation document, falling back to the
+/// EPUB 2 NCX when the book doesn't have one.
+fn get_toc(z: &mut Archive, package: &Package) -> Result<Vec<TocItem>, ConvertError> {
+    if let Some(nav) = package
+        .manifest
+        .iter()
+        .find(|item| item.has_property("nav"))
+    {
+        let path = package.path_of(&nav.href);
+        let f = BufReader::new(
+            z.by_name(&path)
+                .map_err(|e| ConvertError::from_zip(&path, e))?,
+        );
+
+        return parse_nav(f, parent_dir(&path)).map_err(|error| ConvertError::Xml { path, error });
+    }
+
+    if let Some(ncx) = package
+        .manifest
+        .iter()
+        .find(|item| item.media_type == "application/x-dtbncx+xml")
+    {
+        let path = package.path_of(&ncx.href);
+        let f = BufReader::new(
+            z.by_name(&path)
+                .map_err(|e| ConvertError::from_zip(&path, e))?,
+        );
+
+        return parse_ncx(f, parent_dir(&path)).map_err(|error| ConvertError::Xml { path, error });
+    }
+
+    Ok(Vec::new())
+}
+
+fn get_root_file_path(z: &mut Archive) -> Result<String, ConvertError> {
+    const CONTAINER_PATH: &str = "META-INF/container.xml";
+
+    let container = z
+        .by_name(CONTAINER_PATH)
+        .map_err(|e| ConvertError::from_zip(CONTAINER_PATH, e))?;
+    let container = BufReader::with_capacity(256, container);
+
+    let mut reader = Reader::from_reader(container);
+    let config = reader.config_mut();
+    config.expand_empty_elements = true;
+    config.check_end_names = false;
+    config.trim_markup_names_in_closing_tags = false;
+
+    let mut buf = Vec::with_capacity(64);
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(quick_xml::events::Event::Start(e)) if e.name().as_ref() == b"rootfile" => {
+                if let Some(path) = get_attr_string(e.attributes(), b"full-path") {
+                    return Ok(path);
+                }
+            }
+            Err(e) => {
+                return Err(ConvertError::Xml {
+                    path: CONTAINER_PATH.to_string(),
+                    error: XmlError::read(&reader, e),
+                });
+            }
+            Ok(quick_xml::events::Event::Eof) => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Err(ConvertError::MissingRootFile)
+}
+
+/// get_image_files finds the images in the manifest. Images which are listed but aren't in the
+/// archive are left out, so references to them are treated like any other unknown image.
+fn get_image_files(z: &mut Archive, package: &Package) -> Result<ImageFiles, ConvertError> {
+    let mut indices = HashMap::with_capacity(12);
+    let mut media_type_formats = Vec::with_capacity(12);
+    let mut uncompressed_lengths = Vec::with_capacity(12);
+    let mut file_numbers = Vec::with_capacity(12);
+
+    for item in package.manifest.iter().filter(|item| item.is_image()) {
+        let path = package.path_of(&item.href);
+        if indices.contains_key(&path) {
+            continue;
+        }
+
+        let Some(i) = z.index_for_name(&path) else {
+            continue;
+        };
+        let f = z.by_index(i).map_err(ConvertError::Zip)?;
+
+        // Image indices are stored as a u32
+        let index = u32::try_from(file_numbers.len()).map_err(|_| {
+            ConvertError::TooLarge(format!("more than {} images are not supported", u32::MAX))
+        })?;
+        indices.insert(path, index);
+
+        media_type_formats.push(format_of_media_type(&item.media_type));
+        uncompressed_lengths.push(f.size());
+        file_numbers.push(i);
+    }
+
+    Ok(ImageFiles {
+        indices,
+        media_type_formats: media_type_formats.into_boxed_slice(),
+        uncompressed_lengths: uncompressed_lengths.into_boxed_slice(),
+        file_numbers: file_numbers.into_boxed_slice(),
+    })
+}
+
+enum GaijiParseState {
+    InName { start: usize },
+    InReplacement { start: usize },
+    NameNext,
+    ReplacementNext,
+}
+
+fn get_gaiji(z: &mut Archive) -> Result<Gaiji, ConvertError> {
+    const GAIJI_PATH: &str = "gaiji.json";
+
+    let mut f = match z.by_name(GAIJI_PATH) {
+        Ok(f) => f,
+        Err(ZipError::FileNotFound) => return Ok(Gaiji::default()),
+        Err(e) => return Err(ConvertError::Zip(e)),
+    };
+
+    let mut content = String::with_capacity(32);
+    f.read_to_string(&mut content)
+        .map_err(|source| ConvertError::ReadEntry {
+            path: GAIJI_PATH.to_string(),
+            source,
+        })?;
+
+    let mut replacements = HashMap::with_capacity(1);
+
+    let mut name = "";
+    let mut state = GaijiParseState::NameNext;
+    for (i, ch) in content.char_indices() {
+        if ch != '"' {
+            continue;
+        }
+
+        match state {
+            GaijiParseState::InName { start } => {
+                name = &content[start..i];
+                state = GaijiParseState::ReplacementNext;
+            }
+            GaijiParseState::InReplacement { start } => {
+                replacements.insert(name.to_string(), content[start..i].encode_utf16().collect());
+                state = GaijiParseState::NameNext;
+            }
+            GaijiParseState::NameNext => state = GaijiParseState::InName { start: i + 1 },
+            GaijiParseState::ReplacementNext => {
+                state = GaijiParseState::InReplacement { start: i + 1 }
+            }
+        }
+    }
+
+    Ok(Gaiji { replacements })
+}
+
+/// get_stylesheets reads the CSS files in the manifest. Files which are listed but aren't in the
+/// archive are left out, like images.
+fn get_stylesheets(z: &mut Archive, package: &Package) -> Result<Stylesheets, ConvertError> {
+    let mut by_path = HashMap::new();
+
+    for item in package
+        .manifest
+        .iter()
+        .filter(|item| item.media_type == "text/css")
+    {
+        let path = package.path_of(&item.href);
+        let mut f = match z.by_name(&path) {
+            Ok(f) => f,
+            Err(ZipError::FileNotFound) => continue,
+            Err(e) => return Err(ConvertError::from_zip(&path, e)),
+        };
+
+        let mut content = Vec::with_capacity(f.size().try_into().unwrap_or(0));
+        f.read_to_end(&mut content)
+            .map_err(|source| ConvertError::ReadEntry {
+                path: path.clone(),
+                source,
+            })?;
+
+        by_path.insert(path, parse_stylesheet(&String::from_utf8_lossy(&content)));
+    }
+
+    Ok(Stylesheets { by_path })
+}
+
+/// embed_images reads the cover image and the images that `blocks` refer to, in the order that
+/// they're first referred to, re-encodes them according to `options`, and stores identical images
+/// once. The blocks are updated to refer to the index of their image in the result, and the index
+/// of the cover is returned alongside.
+fn embed_images(
+    input_path: &Path,
+    image_files: &ImageFiles,
+    cover: Option<u32>,
+    options: &ImageOptions,
+    blocks: &mut [ContentBlock],
+) -> Result<(Vec<Image>, ImageSavings, Option<u32>), ConvertError> {
+    let (referenced, referenced_indices) =
+        referenced_images(cover, blocks, image_files.file_numbers.len());
+
+    let unreferenced_bytes = referenced_indices
+        .iter()
+        .zip(&image_files.uncompressed_lengths)
+        .filter(|(index, _)| index.is_none())
+        .map(|(_, &len)| len)
+        .sum();
+
+    let (data, original_lens): (Vec<_>, Vec<_>) =
+        read_images(input_path, image_files, &referenced, options)?
+            .into_iter()
+            .unzip();
+    let reencoded_bytes = data
+        .iter()
+        .zip(&original_lens)
+        .filter_map(|(image, original_len)| Some((*original_len)? as i64 - image.data.len() as i64))
+        .sum();
+
+    let num_referenced = data.len();
+    let referenced_bytes = total_len(&data);
+    let (images, stored_indices) = deduplicate(data);
+
+    for block in blocks.iter_mut() {
+        if let ContentBlock::Image { index } = block
+            && let Some(referenced_idx) = referenced_indices[*index as usize]
+        {
+            *index = stored_indices[referenced_idx];
+        }
+    }
+
+    let savings = ImageSavings {
+        unreferenced: image_files.file_numbers.len() - num_referenced,
+        unreferenced_bytes,
+        duplicates: num_referenced - images.len(),
+        duplicate_bytes: referenced_bytes - total_len(&images),
+        reencoded: original_lens.iter().flatten().count(),
+        reencoded_bytes,
+    };
+
+    let cover = cover
+        .and_then(|index| referenced_indices[index as usize])
+        .map(|referenced_idx| stored_indices[referenced_idx]);
+
+    Ok((images, savings, cover))
+}
+
+fn total_len(images: &[Image]) -> u64 {
+    images.iter().map(|image| image.data.len() as u64).sum()
+}
+
+/// referenced_images returns the indices of the image files that `blocks` refer to, in the order
+/// that they're first referred to, after the cover image which is always included. Along with
+/// them, it returns the position of each image file within the referenced images.
+fn referenced_images(
+    cover: Option<u32>,
+    blocks: &[ContentBlock],
+    num_image_files: usize,
+) -> (Vec<usize>, Vec<Option<usize>>) {
+    let mut referenced = Vec::new();
+    let mut positions = vec![None; num_image_files];
+
+    let block_images = blocks.iter().filter_map(|block| match *block {
+        ContentBlock::Image { index } => Some(index),
+        ContentBlock::Text { .. } => None,
+    });
+    for index in cover.into_iter().chain(block_images) {
+        positions[index as usize].get_or_insert_with(|| {
+            referenced.push(index as usize);
+            referenced.len() - 1
+        });
+    }
+
+    (referenced, positions)
+}
+
+/// deduplicate keeps the first of each set of identical images. Along with the images that are
+/// kept, it returns the index in the result of each of `images`.
+fn deduplicate(images: Vec<Image>) -> (Vec<Image>, Vec<u32>) {
+    let mut indices = Vec::with_capacity(images.len());
+    {
+        let mut by_content = HashMap::with_capacity(images.len());
+        for image in &images {
+            let num_kept = by_content.len();
+            // get_image_files ensures that there are few enough images for this to fit
+            indices.push(*by_content.entry(&*image.data).or_insert(num_kept as u32));
+        }
+    }
+
+    let mut num_kept = 0;
+    let kept = images
+        .into_iter()
+        .zip(&indices)
+        .filter_map(|(image, &index)| {
+            let is_first = index == num_kept;
+            num_kept += u32::from(is_first);
+            is_first.then_some(image)
+        })
+        .collect();
+
+    (kept, indices)
+}
+
+/// read_images reads the content of the image files at `indices`, in order, and re-encodes them
+/// according to `options`. Each image is returned along with its size in the EPUB if it was
+/// re-encoded.
+fn read_images(
+    input_path: &Path,
+    image_files: &ImageFiles,
+    indices: &[usize],
+    options: &ImageOptions,
+) -> Result<Vec<(Image, Option<u64>)>, ConvertError> {
+    // Start with the largest images so that the work is spread evenly between threads
+    let mut numbers = indices.iter().copied().enumerate().collect::<Vec<_>>();
+    numbers.sort_by_key(|&(_, i)| Reverse(image_files.uncompressed_lengths[i]));
+
+    let mut result = numbers
+        .par_iter()
+        .map(|&(position, i)| {
+            let mut archive = ZipArchive::new(open(input_path)?).map_err(ConvertError::Zip)?;
+
+            let mut f = archive
+                .by_index(image_files.file_numbers[i])
+                .map_err(ConvertError::Zip)?;
+
+            let mut buf =
+                Vec::with_capacity(image_files.uncompressed_lengths[i].try_into().unwrap_or(0));
+            f.read_to_end(&mut buf)
+                .map_err(|source| ConvertError::ReadEntry {
+                    path: f.name().to_string(),
+                    source,
+                })?;
+
+            let format = detect_format(&buf, image_files.media_type_formats[i]);
+            let (width, height) = dimensions(&buf, format).unwrap_or_default();
+            let image = Image {
+                data: buf.into_boxed_slice(),
+                format,
+                width,
+                height,
+            };
+
+            Ok(match reencode(&image, options) {
+                Some(reencoded) => (position, reencoded, Some(image.data.len() as u64)),
+                None => (position, image, None),
+            })
+        })
+        .collect::<Result<Vec<_>, ConvertError>>()?;
+
+    result.sort_by_key(|&(position, ..)| position);
+
+    Ok(result
+        .into_iter()
+        .map(|(_, image, original_len)| (image, original_len))
+        .collect())
+}
+
+fn parse_paragraphs(
+    input_path: &Path,
+    text_files: &TextFiles,
+    image_files: &ImageFiles,
+    gaiji: Gaiji,
+    stylesheets: &Stylesheets,
+    profile: &Profile,
+    error_mode: ErrorMode,
+) -> Result<Vec<ParsedText>, ConvertError> {
+    let input = text_files
+        .file_numbers
+        .iter()
+        .copied()
+        .zip(&text_files.paths)
+        .enumerate()
+        .collect::<Vec<_>>();
+
+    let mut result = input
+        .par_iter()
+        .map(|&(i, (num, path))| {
+            let mut archive = ZipArchive::new(open(input_path)?).map_err(ConvertError::Zip)?;
+            let mut f = archive.by_index(num).map_err(ConvertError::Zip)?;
+            let mut buf = String::with_capacity(f.size().try_into().unwrap_or(0));
+            f.read_to_string(&mut buf)
+                .map_err(|source| ConvertError::ReadEntry {
+                    path: path.clone(),
+                    source,
+                })?;
+
+            let text = parse_text_file(
+                &buf,
+                parent_dir(path),
+                image_files,
+                &gaiji,
+                stylesheets,
+                profile,
+                error_mode,
+            )
+            .map_err(|error| ConvertError::Text {
+                path: path.clone(),
+                error,
+            })?;
+
+            Ok((i, text))
+        })
+        .collect::<Result<Vec<_>, ConvertError>>()?;
+
+    result.sort_by_key(|&(i, _)| i);
+
+    Ok(result.into_iter().map(|(_, text)| text).collect())
+}
+
+/// text_str returns the raw contents of a text event.
+fn text_str(bytes: &[u8]) -> Result<&str, quick_xml::Error> {
+    str::from_utf8(bytes).map_err(|e| quick_xml::encoding::EncodingError::from(e).into())
+}
+
+/// unescape replaces the entity references (e.g. `&amp;` or `&nbsp;`) and character references
+/// (e.g. `&#x2015;`) in text. Any entity defined by HTML is recognized, which includes those of
+/// XHTML. References which aren't recognized are left as they are.
+fn unescape(raw: &str) -> Cow<'_, str> {
+    if !raw.contains('&') {
+        return Cow::Borrowed(raw);
+    }
+
+    let mut result = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        // Entity names are short, so a far away `;` isn't the end of a reference
+        let reference = rest
+            .char_indices()
+            .take(32)
+            .find(|&(_, c)| c == ';')
+            .map(|(end, _)| &rest[..end]);
+        match reference.and_then(|reference| Some((reference, resolve_reference(reference)?))) {
+            Some((reference, resolved)) => {
+                result.push_str(&resolved);
+                rest = &rest[reference.len() + 1..];
+            }
+            None => result.push('&'),
+        }
+    }
+    result.push_str(rest);
+
+    Cow::Owned(result)
+}
+
+/// resolve_reference returns the text for the contents of an entity or character reference,
+/// which is between the `&` and `;`.
+fn resolve_reference(reference: &str) -> Option<Cow<'static, str>> {
+    let Some(number) = reference.strip_prefix('#') else {
+        return resolve_html5_entity(reference).map(Cow::Borrowed);
+    };
+
+    let code = match number.strip_prefix(['x', 'X']) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => number.parse(),
+    };
+    // NUL isn't allowed in XML
+    let c = char::from_u32(code.ok()?).filter(|&c| c != '\0')?;
+
+    Some(Cow::Owned(c.to_string()))
+}
+
+/// get_attr returns the value of the attribute with the local name `key`. Malformed attributes
+/// are skipped.
+fn get_attr<'a>(mut attributes: Attributes<'a>, key: &'static [u8]) -> Option<Cow<'a, [u8]>> {
+    for attr in attributes.with_checks(false).flatten() {
+        if attr.key.local_name().as_ref() == key {
+            return Some(attr.value);
+        }
+    }
+
+    None
+}
+
+fn get_attr_string(attributes: Attributes<'_>, key: &'static [u8]) -> Option<String> {
+    get_attr(attributes, key).map(|value| String::from_utf8_lossy(&value).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(data: &[u8]) -> Image {
+        Image {
+            data: data.into(),
+            format: ImageFormat::Unknown,
+            width: 0,
+            height: 0,
+        }
+    }
+
+    #[test]
+    fn referenced_images_in_order() {
+        let blocks = [
+            ContentBlock::Image { index: 3 },
+            ContentBlock::Text {
+                text: Box::new([]),
+                ruby: Box::new([]),
+                secondary_ruby: Box::new([]),
+                spans: Box::new([]),
+                emphasis: Box::new([]),
+                flags: 0,
+                heading_level: 0,
+            },
+            ContentBlock::Image { index: 1 },
+            ContentBlock::Image { index: 3 },
+        ];
+
+        let (referenced, positions) = referenced_images(None, &blocks, 4);
+
+        assert_eq!(referenced, [3, 1]);
+        assert_eq!(positions, [None, Some(1), None, Some(0)]);
+
+        let (referenced, positions) = referenced_images(Some(0), &blocks, 4);
+
+        assert_eq!(referenced, [0, 3, 1]);
+        assert_eq!(positions, [Some(0), Some(2), None, Some(1)]);
+    }
+
+    #[test]
+    fn deduplicate_identical_images() {
+        let images = vec![image(&[1]), image(&[2, 2]), image(&[1]), image(&[3])];
+
+        let (kept, indices) = deduplicate(images);
+
+        assert_eq!(kept, [image(&[1]), image(&[2, 2]), image(&[3])]);
+        assert_eq!(indices, [0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn unescape_references() {
+        assert_eq!(unescape("本"), "本");
+        assert_eq!(
+            unescape("&lt;&gt;&amp;&quot;&apos;"),
+            "<>&\"'",
+            "predefined entities"
+        );
+        assert_eq!(
+            unescape("&#12354;&#x2015;&#X2015;"),
+            "あ――",
+            "character references"
+        );
+        assert_eq!(
+            unescape("a&nbsp;b&hellip;&mdash;&copy;"),
+            "a\u{a0}b…—©",
+            "XHTML entities"
+        );
+        assert_eq!(
+            unescape("a & b &unknown; &#xd800; &#0; &amp"),
+            "a & b &unknown; &#xd800; &#0; &amp",
+            "unrecognized references"
+        );
+    }
+}
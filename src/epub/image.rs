@@ -0,0 +1,261 @@
+//! Detection of the format and size of images, without decoding them.
+
+use crate::ImageFormat;
+
+/// format_of_media_type returns the format for the media type of an item in the manifest.
+pub(super) fn format_of_media_type(media_type: &str) -> ImageFormat {
+    match media_type {
+        "image/jpeg" => ImageFormat::Jpeg,
+        "image/png" => ImageFormat::Png,
+        "image/gif" => ImageFormat::Gif,
+        "image/webp" => ImageFormat::WebP,
+        "image/svg+xml" => ImageFormat::Svg,
+        "image/avif" => ImageFormat::Avif,
+        _ => ImageFormat::Unknown,
+    }
+}
+
+/// sniff_format detects the format of an image from its content. SVG is only detected when the
+/// start of the file contains an `<svg>` element, since it doesn't have a signature.
+pub(super) fn sniff_format(data: &[u8]) -> ImageFormat {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        return ImageFormat::Jpeg;
+    }
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return ImageFormat::Png;
+    }
+
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return ImageFormat::Gif;
+    }
+
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        return ImageFormat::WebP;
+    }
+
+    if is_avif(data) {
+        return ImageFormat::Avif;
+    }
+
+    let start = &data[..data.len().min(1024)];
+    if start.windows(4).any(|w| w == b"<svg") {
+        return ImageFormat::Svg;
+    }
+
+    ImageFormat::Unknown
+}
+
+/// is_avif returns whether `data` starts with an ISO BMFF `ftyp` box whose major or compatible
+/// brands include AVIF.
+fn is_avif(data: &[u8]) -> bool {
+    if data.get(4..8) != Some(b"ftyp") {
+        return false;
+    }
+
+    let box_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let Some(ftyp) = data.get(8..box_len.min(data.len())) else {
+        return false;
+    };
+
+    // The major brand, the minor version, then the compatible brands
+    ftyp.chunks_exact(4)
+        .enumerate()
+        .any(|(i, brand)| i != 1 && (brand == b"avif" || brand == b"avis"))
+}
+
+/// detect_format returns the format of an image, preferring its content over the media type that
+/// the manifest gives it, since some books list images with the wrong media type.
+pub(super) fn detect_format(data: &[u8], media_type_format: ImageFormat) -> ImageFormat {
+    match sniff_format(data) {
+        ImageFormat::Unknown => media_type_format,
+        format => format,
+    }
+}
+
+/// dimensions returns the width and height of an image in pixels, which are read from its header.
+/// It's `None` when the header is malformed, or for formats whose size isn't read.
+pub(super) fn dimensions(data: &[u8], format: ImageFormat) -> Option<(u32, u32)> {
+    match format {
+        ImageFormat::Jpeg => jpeg_dimensions(data),
+        ImageFormat::Png => {
+            // The IHDR chunk is first, after the signature, and its length and type
+            if data.get(12..16)? != b"IHDR" {
+                return None;
+            }
+            Some((be_u32(data, 16)?, be_u32(data, 20)?))
+        }
+        ImageFormat::Gif => Some((le_u16(data, 6)?.into(), le_u16(data, 8)?.into())),
+        ImageFormat::WebP => webp_dimensions(data),
+        ImageFormat::Svg | ImageFormat::Avif | ImageFormat::Unknown => None,
+    }
+}
+
+/// jpeg_dimensions reads the size from the start of frame (SOF) segment.
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    // Skip the start of image marker
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xff {
+            return None;
+        }
+
+        // Markers can be preceded by any number of fill bytes
+        while *data.get(pos + 1)? == 0xff {
+            pos += 1;
+        }
+
+        let marker = data[pos + 1];
+        pos += 2;
+
+        // Markers without a segment
+        if marker == 0x01 || (0xd0..=0xd9).contains(&marker) {
+            continue;
+        }
+
+        // SOF0 to SOF15, except for DHT, JPG and DAC which share the range
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            // The segment length, then the sample precision
+            let height = be_u16(data, pos + 3)?;
+            let width = be_u16(data, pos + 5)?;
+            return Some((width.into(), height.into()));
+        }
+
+        pos += usize::from(be_u16(data, pos)?);
+    }
+}
+
+/// webp_dimensions reads the size from the first chunk of a WebP file, which depends on whether
+/// it's lossy (`VP8 `), lossless (`VP8L`) or extended (`VP8X`).
+fn webp_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    match data.get(12..16)? {
+        b"VP8 " => {
+            // The frame tag (3 bytes) and the start code come before the size
+            if data.get(23..26)? != [0x9d, 0x01, 0x2a] {
+                return None;
+            }
+            let width = le_u16(data, 26)? & 0x3fff;
+            let height = le_u16(data, 28)? & 0x3fff;
+            Some((width.into(), height.into()))
+        }
+        b"VP8L" => {
+            if *data.get(20)? != 0x2f {
+                return None;
+            }
+            // 14 bits each for the width and height, minus one
+            let bits = u32::from_le_bytes(data.get(21..25)?.try_into().ok()?);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => {
+            // Flags (4 bytes), then 24 bits each for the canvas width and height, minus one
+            let le_u24 = |offset| {
+                let bytes = data.get(offset..offset + 3)?;
+                Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) + 1)
+            };
+            Some((le_u24(24)?, le_u24(27)?))
+        }
+        _ => None,
+    }
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn le_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_formats() {
+        assert_eq!(sniff_format(&[0xff, 0xd8, 0xff, 0xe0]), ImageFormat::Jpeg);
+        assert_eq!(sniff_format(b"\x89PNG\r\n\x1a\n\0\0"), ImageFormat::Png);
+        assert_eq!(sniff_format(b"GIF89a\x01\0"), ImageFormat::Gif);
+        assert_eq!(sniff_format(b"RIFF\x10\0\0\0WEBPVP8 "), ImageFormat::WebP);
+        assert_eq!(
+            sniff_format(b"\0\0\0\x1cftypmif1\0\0\0\0mif1avifmiaf"),
+            ImageFormat::Avif
+        );
+        assert_eq!(
+            sniff_format(b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\">"),
+            ImageFormat::Svg
+        );
+        assert_eq!(sniff_format(b"<html>"), ImageFormat::Unknown);
+        assert_eq!(sniff_format(b""), ImageFormat::Unknown);
+    }
+
+    #[test]
+    fn avif_brand_is_not_minor_version() {
+        assert_eq!(
+            sniff_format(b"\0\0\0\x14ftypmif1avifmiaf"),
+            ImageFormat::Unknown
+        );
+    }
+
+    #[test]
+    fn jpeg_dimensions() {
+        let mut jpeg = vec![0xff, 0xd8];
+        // An APP0 segment, a fill byte and then SOF2
+        jpeg.extend_from_slice(&[0xff, 0xe0, 0x00, 0x04, 0x00, 0x00]);
+        jpeg.extend_from_slice(&[0xff, 0xff, 0xc2, 0x00, 0x11, 0x08, 0x01, 0xe0, 0x02, 0x80]);
+
+        assert_eq!(dimensions(&jpeg, ImageFormat::Jpeg), Some((640, 480)));
+        assert_eq!(dimensions(&jpeg[..10], ImageFormat::Jpeg), None);
+    }
+
+    #[test]
+    fn png_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&300u32.to_be_bytes());
+        png.extend_from_slice(&200u32.to_be_bytes());
+
+        assert_eq!(dimensions(&png, ImageFormat::Png), Some((300, 200)));
+    }
+
+    #[test]
+    fn gif_dimensions() {
+        assert_eq!(
+            dimensions(b"GIF89a\x2c\x01\xc8\x00", ImageFormat::Gif),
+            Some((300, 200))
+        );
+    }
+
+    #[test]
+    fn webp_dimensions() {
+        let mut lossy = b"RIFF\0\0\0\0WEBPVP8 \0\0\0\0\0\0\0\x9d\x01\x2a".to_vec();
+        lossy.extend_from_slice(&300u16.to_le_bytes());
+        lossy.extend_from_slice(&200u16.to_le_bytes());
+        assert_eq!(dimensions(&lossy, ImageFormat::WebP), Some((300, 200)));
+
+        let mut lossless = b"RIFF\0\0\0\0WEBPVP8L\0\0\0\0\x2f".to_vec();
+        lossless.extend_from_slice(&(299u32 | 199 << 14).to_le_bytes());
+        assert_eq!(dimensions(&lossless, ImageFormat::WebP), Some((300, 200)));
+
+        let mut extended = b"RIFF\0\0\0\0WEBPVP8X\0\0\0\0\0\0\0\0".to_vec();
+        extended.extend_from_slice(&[0x2b, 0x01, 0x00, 0xc7, 0x00, 0x00]);
+        assert_eq!(dimensions(&extended, ImageFormat::WebP), Some((300, 200)));
+    }
+
+    #[test]
+    fn content_takes_precedence() {
+        assert_eq!(
+            detect_format(b"\x89PNG\r\n\x1a\n", ImageFormat::Jpeg),
+            ImageFormat::Png
+        );
+        assert_eq!(detect_format(b"", ImageFormat::Jpeg), ImageFormat::Jpeg);
+    }
+}
@@ -0,0 +1,622 @@
+//! Parsing of the package document (OPF), which lists the files of the book and its metadata.
+
+use super::{XmlError, get_attr_string, text_str, unescape};
+use crate::{Creator, Metadata, Series};
+use quick_xml::Reader;
+use std::{borrow::Cow, io::BufRead, str};
+
+#[derive(Debug, Default)]
+pub(super) struct Package {
+    /// dir is the directory within the archive which contains the package document. hrefs in the
+    /// package are relative to it.
+    pub(super) dir: String,
+    pub(super) metadata: Metadata,
+    pub(super) manifest: Vec<ManifestItem>,
+    spine: Vec<SpineItem>,
+    /// cover_id is the content of the EPUB 2 `<meta name="cover">`, which is the id of the
+    /// manifest item of the cover image.
+    cover_id: Option<String>,
+}
+
+/// MetadataField is an element within `<metadata>` whose text is being read.
+enum MetadataField {
+    Title,
+    Creator {
+        id: Option<String>,
+        role: String,
+    },
+    Language,
+    Publisher,
+    Identifier {
+        id: Option<String>,
+    },
+    Date,
+    Meta {
+        id: Option<String>,
+        refines: Option<String>,
+        property: String,
+    },
+}
+
+/// Refinement is an EPUB 3 `<meta>` which adds information to another element in `<metadata>`,
+/// e.g. the role of a creator.
+struct Refinement {
+    id: String,
+    property: String,
+    value: String,
+}
+
+#[derive(Debug)]
+pub(super) struct ManifestItem {
+    id: String,
+    pub(super) href: String,
+    pub(super) media_type: String,
+    properties: String,
+}
+
+#[derive(Debug)]
+struct SpineItem {
+    idref: String,
+    /// linear is false for items marked with `linear="no"`, which are auxiliary content that
+    /// isn't part of the main reading order (e.g. footnotes).
+    linear: bool,
+}
+
+impl ManifestItem {
+    fn is_html(&self) -> bool {
+        self.media_type == "application/xhtml+xml"
+    }
+
+    /// is_image returns whether the item is an image. Items with a generic media type are
+    /// included when they have the extension of an image, and their format is detected from their
+    /// content.
+    pub(super) fn is_image(&self) -> bool {
+        const EXTENSIONS: [&str; 7] = ["jpg", "jpeg", "png", "gif", "webp", "svg", "avif"];
+
+        if self.media_type.starts_with("image/") {
+            return true;
+        }
+
+        let (path, _) = split_fragment(&self.href);
+        matches!(self.media_type.as_str(), "" | "application/octet-stream")
+            && path.rsplit_once('.').is_some_and(|(_, ext)| {
+                EXTENSIONS
+                    .iter()
+                    .any(|image_ext| ext.eq_ignore_ascii_case(image_ext))
+            })
+    }
+
+    pub(super) fn has_property(&self, property: &str) -> bool {
+        self.properties.split(' ').any(|p| p == property)
+    }
+}
+
+impl Package {
+    /// cover_href returns the href of the cover image: the item with the `cover-image` property
+    /// (EPUB 3), or else the item that `<meta name="cover">` points to (EPUB 2). Some books put
+    /// the href instead of the id of the item in the `<meta>`, so that is accepted too.
+    pub(super) fn cover_href(&self) -> Option<&str> {
+        let images = || self.manifest.iter().filter(|item| item.is_image());
+        images()
+            .find(|item| item.has_property("cover-image"))
+            .or_else(|| {
+                let id = self.cover_id.as_deref()?;
+                images()
+                    .find(|item| item.id == id)
+                    .or_else(|| images().find(|item| item.href == id))
+            })
+            .map(|item| item.href.as_str())
+    }
+
+    /// path_of returns the path within the archive of the file that `href` points to.
+    pub(super) fn path_of(&self, href: &str) -> String {
+        resolve_href(&self.dir, href)
+    }
+
+    /// text_hrefs returns the hrefs of the text files in reading order. This is the order of the
+    /// spine, or the order of the manifest when the package doesn't have a spine.
+    pub(super) fn text_hrefs(&self, include_non_linear: bool) -> impl Iterator<Item = &str> {
+        let from_manifest = self.spine.is_empty().then(|| {
+            self.manifest
+                .iter()
+                .filter(|item| item.is_html())
+                .map(|item| item.href.as_str())
+        });
+
+        let from_spine = self
+            .spine
+            .iter()
+            .filter(move |itemref| include_non_linear || itemref.linear)
+            .filter_map(|itemref| self.manifest.iter().find(|item| item.id == itemref.idref))
+            .filter(|item| item.is_html())
+            .map(|item| item.href.as_str());
+
+        from_manifest.into_iter().flatten().chain(from_spine)
+    }
+}
+
+pub(super) fn parse_package(root_file: impl BufRead) -> Result<Package, XmlError> {
+    let mut reader = Reader::from_reader(root_file);
+    let config = reader.config_mut();
+    config.expand_empty_elements = true;
+    config.check_end_names = false;
+    config.trim_markup_names_in_closing_tags = false;
+
+    let mut package = Package::default();
+
+    let mut unique_identifier = None;
+    let mut in_metadata = false;
+    let mut field = None;
+    let mut text = String::new();
+    let mut creator_ids = Vec::new();
+    let mut series_id = None;
+    let mut refinements = Vec::new();
+
+    let mut buf = Vec::with_capacity(128);
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(quick_xml::events::Event::Start(e)) if e.local_name().as_ref() == b"package" => {
+                unique_identifier = get_attr_string(e.attributes(), b"unique-identifier");
+            }
+            Ok(quick_xml::events::Event::Start(e)) if e.local_name().as_ref() == b"metadata" => {
+                in_metadata = true;
+            }
+            Ok(quick_xml::events::Event::Start(e)) if in_metadata => {
+                text.clear();
+                field = match e.local_name().as_ref() {
+                    b"title" => Some(MetadataField::Title),
+                    b"creator" => Some(MetadataField::Creator {
+                        id: get_attr_string(e.attributes(), b"id"),
+                        role: get_attr_string(e.attributes(), b"role").unwrap_or_default(),
+                    }),
+                    b"language" => Some(MetadataField::Language),
+                    b"publisher" => Some(MetadataField::Publisher),
+                    b"identifier" => Some(MetadataField::Identifier {
+                        id: get_attr_string(e.attributes(), b"id"),
+                    }),
+                    b"date" => Some(MetadataField::Date),
+                    b"meta" => match get_attr_string(e.attributes(), b"property") {
+                        Some(property) => Some(MetadataField::Meta {
+                            id: get_attr_string(e.attributes(), b"id"),
+                            refines: get_attr_string(e.attributes(), b"refines"),
+                            property,
+                        }),
+                        None => {
+                            // EPUB 2 style <meta name="..." content="..."/>
+                            let name = get_attr_string(e.attributes(), b"name");
+                            let content = get_attr_string(e.attributes(), b"content");
+                            if let (Some(name), Some(content)) = (name, content) {
+                                let metadata = &mut package.metadata;
+                                match name.as_str() {
+                                    "calibre:series" => {
+                                        metadata.series.get_or_insert_default().name = content;
+                                    }
+                                    "calibre:series_index" => {
+                                        metadata.series.get_or_insert_default().index = content;
+                                    }
+                                    "cover" => package.cover_id = Some(content),
+                                    _ => {}
+                                }
+                            }
+
+                            None
+                        }
+                    },
+                    _ => None,
+                };
+            }
+            Ok(quick_xml::events::Event::Text(e)) if field.is_some() => {
+                text.push_str(&unescape(
+                    text_str(&e).map_err(|err| XmlError::at(&reader, err))?,
+                ));
+            }
+            Ok(quick_xml::events::Event::End(e)) if in_metadata => {
+                if e.local_name().as_ref() == b"metadata" {
+                    in_metadata = false;
+                }
+
+                let Some(field) = field.take() else {
+                    buf.clear();
+                    continue;
+                };
+
+                let value = text.trim().to_string();
+                let metadata = &mut package.metadata;
+                match field {
+                    MetadataField::Title if metadata.title.is_empty() => metadata.title = value,
+                    MetadataField::Creator { id, role } => {
+                        creator_ids.push(id);
+                        metadata.creators.push(Creator { name: value, role });
+                    }
+                    MetadataField::Language if metadata.language.is_empty() => {
+                        metadata.language = value;
+                    }
+                    MetadataField::Publisher if metadata.publisher.is_empty() => {
+                        metadata.publisher = value;
+                    }
+                    MetadataField::Identifier { id }
+                        if metadata.identifier.is_empty()
+                            || (id.is_some() && id == unique_identifier) =>
+                    {
+                        metadata.identifier = value;
+                    }
+                    MetadataField::Date if metadata.date.is_empty() => metadata.date = value,
+                    MetadataField::Meta {
+                        id,
+                        refines: None,
+                        property,
+                    } if property == "belongs-to-collection" && series_id.is_none() => {
+                        metadata.series = Some(Series {
+                            name: value,
+                            index: String::new(),
+                        });
+                        series_id = Some(id);
+                    }
+                    MetadataField::Meta {
+                        refines: Some(refines),
+                        property,
+                        ..
+                    } => refinements.push(Refinement {
+                        id: refines.trim_start_matches('#').to_string(),
+                        property,
+                        value,
+                    }),
+                    _ => {}
+                }
+            }
+            Ok(quick_xml::events::Event::Start(e)) if e.local_name().as_ref() == b"item" => {
+                let mut item = ManifestItem {
+                    id: String::new(),
+                    href: String::new(),
+                    media_type: String::new(),
+                    properties: String::new(),
+                };
+
+                for attr in e.attributes().with_checks(false).flatten() {
+                    let value = String::from_utf8_lossy(&attr.value).into_owned();
+                    match attr.key.as_ref() {
+                        b"id" => item.id = value,
+                        b"href" => item.href = value,
+                        b"media-type" => item.media_type = value,
+                        b"properties" => item.properties = value,
+                        _ => {}
+                    }
+                }
+
+                package.manifest.push(item);
+            }
+            Ok(quick_xml::events::Event::Start(e)) if e.local_name().as_ref() == b"itemref" => {
+                let mut itemref = SpineItem {
+                    idref: String::new(),
+                    linear: true,
+                };
+
+                for attr in e.attributes().with_checks(false).flatten() {
+                    match attr.key.as_ref() {
+                        b"idref" => {
+                            itemref.idref = String::from_utf8_lossy(&attr.value).into_owned();
+                        }
+                        b"linear" => itemref.linear = *attr.value != *b"no",
+                        _ => {}
+                    }
+                }
+
+                package.spine.push(itemref);
+            }
+            Err(e) => return Err(XmlError::read(&reader, e)),
+            Ok(quick_xml::events::Event::Eof) => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    for refinement in refinements {
+        let id = Some(refinement.id);
+        match refinement.property.as_str() {
+            "role" => {
+                if let Some(i) = creator_ids.iter().position(|creator_id| *creator_id == id) {
+                    package.metadata.creators[i].role = refinement.value;
+                }
+            }
+            "group-position" if series_id.as_ref() == Some(&id) => {
+                if let Some(ref mut series) = package.metadata.series {
+                    series.index = refinement.value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(package)
+}
+
+/// resolve_href returns the path within the archive of the file that `href` points to, where
+/// `base_dir` is the directory of the file that contains the href. The fragment is left out, and
+/// percent-encoded characters are decoded.
+pub(super) fn resolve_href(base_dir: &str, href: &str) -> String {
+    let (href, _) = split_fragment(href);
+    let href = percent_decode(href);
+
+    // An absolute path starts from the root of the archive
+    let base_dir = if href.starts_with('/') { "" } else { base_dir };
+    let mut segments = base_dir
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    segments.join("/")
+}
+
+/// percent_decode decodes the `%XX` escapes in `s`. It's returned unchanged when the escapes don't
+/// decode to UTF-8.
+fn percent_decode(s: &str) -> Cow<'_, str> {
+    if !s.contains('%') {
+        return Cow::Borrowed(s);
+    }
+
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%'
+            && let Some(hex) = tail.get(..2)
+            && hex.iter().all(u8::is_ascii_hexdigit)
+            && let Ok(value) = u8::from_str_radix(str::from_utf8(hex).unwrap_or_default(), 16)
+        {
+            bytes.push(value);
+            rest = &tail[2..];
+            continue;
+        }
+
+        bytes.push(b);
+        rest = tail;
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(decoded) => Cow::Owned(decoded),
+        Err(_) => Cow::Borrowed(s),
+    }
+}
+
+pub(super) fn split_fragment(href: &str) -> (&str, Option<&str>) {
+    match href.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (href, None),
+    }
+}
+
+pub(super) fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(before, _)| before)
+        .unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_hrefs_follow_spine() {
+        let opf = br#"<package>
+            <manifest>
+                <item id="b" href="b.xhtml" media-type="application/xhtml+xml"/>
+                <item id="css" href="style.css" media-type="text/css"/>
+                <item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+                <item id="notes" href="notes.xhtml" media-type="application/xhtml+xml"/>
+            </manifest>
+            <spine>
+                <itemref idref="a"/>
+                <itemref idref="notes" linear="no"/>
+                <itemref idref="b"/>
+            </spine>
+        </package>"#;
+
+        let package = parse_package(opf.as_slice()).unwrap();
+
+        assert_eq!(
+            package.text_hrefs(true).collect::<Vec<_>>(),
+            ["a.xhtml", "notes.xhtml", "b.xhtml"],
+        );
+        assert_eq!(
+            package.text_hrefs(false).collect::<Vec<_>>(),
+            ["a.xhtml", "b.xhtml"],
+        );
+    }
+
+    #[test]
+    fn parse_epub3_metadata() {
+        let opf = r##"<package unique-identifier="uid">
+            <metadata>
+                <dc:title>本</dc:title>
+                <dc:creator id="c1">山田太郎</dc:creator>
+                <meta refines="#c1" property="role" scheme="marc:relators">aut</meta>
+                <dc:creator id="c2">佐藤花子</dc:creator>
+                <meta refines="#c2" property="role" scheme="marc:relators">ill</meta>
+                <dc:language>ja</dc:language>
+                <dc:publisher>出版社</dc:publisher>
+                <dc:identifier id="isbn">urn:isbn:9784000000000</dc:identifier>
+                <dc:identifier id="uid">urn:uuid:1234</dc:identifier>
+                <dc:date>2020-01-02</dc:date>
+                <meta property="belongs-to-collection" id="s">シリーズ</meta>
+                <meta refines="#s" property="group-position">3</meta>
+                <meta property="dcterms:modified">2020-01-03T00:00:00Z</meta>
+            </metadata>
+        </package>"##;
+
+        let package = parse_package(opf.as_bytes()).unwrap();
+
+        assert_eq!(
+            package.metadata,
+            Metadata {
+                title: "本".to_string(),
+                creators: vec![
+                    Creator {
+                        name: "山田太郎".to_string(),
+                        role: "aut".to_string(),
+                    },
+                    Creator {
+                        name: "佐藤花子".to_string(),
+                        role: "ill".to_string(),
+                    },
+                ],
+                language: "ja".to_string(),
+                publisher: "出版社".to_string(),
+                identifier: "urn:uuid:1234".to_string(),
+                date: "2020-01-02".to_string(),
+                series: Some(Series {
+                    name: "シリーズ".to_string(),
+                    index: "3".to_string(),
+                }),
+                cover_image: None,
+            },
+        );
+    }
+
+    #[test]
+    fn parse_epub2_metadata() {
+        let opf = r#"<package>
+            <metadata>
+                <dc:title>本&amp;続&#x2015;</dc:title>
+                <dc:creator opf:role="aut">山田太郎</dc:creator>
+                <dc:identifier>urn:isbn:9784000000000</dc:identifier>
+                <dc:identifier>urn:uuid:00000000-0000-0000-0000-000000000000</dc:identifier>
+                <meta name="calibre:series" content="シリーズ"/>
+                <meta name="calibre:series_index" content="2.5"/>
+            </metadata>
+        </package>"#;
+
+        let package = parse_package(opf.as_bytes()).unwrap();
+
+        assert_eq!(
+            package.metadata,
+            Metadata {
+                title: "本&続―".to_string(),
+                creators: vec![Creator {
+                    name: "山田太郎".to_string(),
+                    role: "aut".to_string(),
+                }],
+                // Without a unique-identifier, the first identifier is kept
+                identifier: "urn:isbn:9784000000000".to_string(),
+                series: Some(Series {
+                    name: "シリーズ".to_string(),
+                    index: "2.5".to_string(),
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn cover_href() {
+        let epub3 = br#"<package>
+            <metadata><meta name="cover" content="other"/></metadata>
+            <manifest>
+                <item id="other" href="other.jpg" media-type="image/jpeg"/>
+                <item id="cover" href="cover.jpg" media-type="image/jpeg" properties="cover-image"/>
+            </manifest>
+        </package>"#;
+        let epub2 = br#"<package>
+            <metadata><meta name="cover" content="cover-image"/></metadata>
+            <manifest>
+                <item id="cover" href="cover.xhtml" media-type="application/xhtml+xml"/>
+                <item id="cover-image" href="images/cover.png" media-type="image/png"/>
+            </manifest>
+        </package>"#;
+        let epub2_href = br#"<package>
+            <metadata><meta name="cover" content="images/cover.png"/></metadata>
+            <manifest>
+                <item id="cover-image" href="images/cover.png" media-type="image/png"/>
+            </manifest>
+        </package>"#;
+        let none = br#"<package>
+            <manifest>
+                <item id="cover" href="cover.jpg" media-type="image/jpeg"/>
+            </manifest>
+        </package>"#;
+
+        let cover_href = |opf: &[u8]| {
+            let package = parse_package(opf).unwrap();
+            package.cover_href().map(str::to_string)
+        };
+        assert_eq!(cover_href(epub3).as_deref(), Some("cover.jpg"));
+        assert_eq!(cover_href(epub2).as_deref(), Some("images/cover.png"));
+        assert_eq!(cover_href(epub2_href).as_deref(), Some("images/cover.png"));
+        assert_eq!(cover_href(none), None);
+    }
+
+    #[test]
+    fn text_hrefs_without_spine() {
+        let opf = br#"<package>
+            <manifest>
+                <item id="b" href="b.xhtml" media-type="application/xhtml+xml"/>
+                <item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+            </manifest>
+        </package>"#;
+
+        let package = parse_package(opf.as_slice()).unwrap();
+
+        assert_eq!(
+            package.text_hrefs(true).collect::<Vec<_>>(),
+            ["b.xhtml", "a.xhtml"],
+        );
+    }
+
+    #[test]
+    fn images_by_media_type() {
+        let opf = br#"<package>
+            <manifest>
+                <item id="a" href="a.JPG" media-type="image/jpeg"/>
+                <item id="b" href="b.svg" media-type="image/svg+xml"/>
+                <item id="c" href="c.webp" media-type="application/octet-stream"/>
+                <item id="d" href="d.bin" media-type="application/octet-stream"/>
+                <item id="e" href="png.xhtml" media-type="application/xhtml+xml"/>
+            </manifest>
+        </package>"#;
+
+        let package = parse_package(opf.as_slice()).unwrap();
+
+        assert_eq!(
+            package
+                .manifest
+                .iter()
+                .filter(|item| item.is_image())
+                .map(|item| item.href.as_str())
+                .collect::<Vec<_>>(),
+            ["a.JPG", "b.svg", "c.webp"],
+        );
+    }
+
+    #[test]
+    fn resolve_relative_href() {
+        assert_eq!(
+            resolve_href("OEBPS/text", "p-001.xhtml"),
+            "OEBPS/text/p-001.xhtml"
+        );
+        assert_eq!(
+            resolve_href("OEBPS/text", "../image/a.jpg"),
+            "OEBPS/image/a.jpg"
+        );
+        assert_eq!(resolve_href("", "./text/p.xhtml#id"), "text/p.xhtml");
+        assert_eq!(resolve_href("OEBPS/text", "/image/a.jpg"), "image/a.jpg");
+    }
+
+    #[test]
+    fn resolve_percent_encoded_href() {
+        assert_eq!(
+            resolve_href("OEBPS", "image/cover%20image.jpg"),
+            "OEBPS/image/cover image.jpg"
+        );
+        assert_eq!(resolve_href("", "%E7%94%BB%E5%83%8F/1.png"), "画像/1.png");
+        assert_eq!(resolve_href("", "100%.png"), "100%.png");
+    }
+}
@@ -0,0 +1,342 @@
+//! Publisher profiles, which describe the class names that a publisher uses for formatting and
+//! 外字, for books whose stylesheets don't describe them.
+
+use super::style::Style;
+use std::{collections::HashMap, fmt, str};
+
+/// Profile is the conventions of a publisher for class names. Profiles are written in a subset of
+/// TOML:
+///
+/// ```toml
+/// # Class names of the <img> elements of 外字, besides `gaiji`
+/// gaiji-classes = ["gaiji-line", "gaiji-wide"]
+///
+/// # CSS declarations that class names stand for
+/// [classes]
+/// gfont = "font-size: 120%"
+/// k-bold = "font-weight: bold"
+/// "太字" = "font-weight: bold"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    classes: HashMap<String, Style>,
+    gaiji_classes: Vec<String>,
+}
+
+/// BUILTIN_PROFILES are the names and contents of the profiles which are built into rnb.
+const BUILTIN_PROFILES: &[(&str, &str)] = &[
+    ("ebpaj", include_str!("profiles/ebpaj.toml")),
+    ("aozora", include_str!("profiles/aozora.toml")),
+];
+
+impl Profile {
+    /// parse parses a profile written in TOML.
+    pub fn parse(toml: &str) -> Result<Self, ProfileError> {
+        let mut profile = Self::default();
+        let mut in_classes = false;
+
+        for (i, line) in toml.lines().enumerate() {
+            let error = |kind| ProfileError { line: i + 1, kind };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(table) = line.strip_prefix('[') {
+                let (table, rest) = table
+                    .split_once(']')
+                    .ok_or(error(ProfileErrorKind::Syntax))?;
+                if !is_comment(rest) {
+                    return Err(error(ProfileErrorKind::Syntax));
+                }
+
+                in_classes = match table.trim() {
+                    "classes" => true,
+                    table => {
+                        return Err(error(ProfileErrorKind::UnknownTable {
+                            name: table.to_string(),
+                        }));
+                    }
+                };
+                continue;
+            }
+
+            let (key, rest) = parse_key(line).ok_or(error(ProfileErrorKind::Syntax))?;
+            let rest = rest
+                .trim_start()
+                .strip_prefix('=')
+                .ok_or(error(ProfileErrorKind::Syntax))?;
+            let invalid_value = || error(ProfileErrorKind::InvalidValue { key: key.clone() });
+
+            if in_classes {
+                let (declarations, rest) =
+                    parse_string(rest.trim_start()).ok_or_else(invalid_value)?;
+                let style = Style::parse(&declarations);
+                if !is_comment(rest) || style == Style::default() {
+                    return Err(invalid_value());
+                }
+
+                profile.classes.insert(key, style);
+            } else if key == "gaiji-classes" {
+                let (classes, rest) = parse_array(rest.trim_start()).ok_or_else(invalid_value)?;
+                if !is_comment(rest) {
+                    return Err(invalid_value());
+                }
+
+                profile.gaiji_classes.extend(classes);
+            } else {
+                return Err(error(ProfileErrorKind::UnknownKey { key }));
+            }
+        }
+
+        Ok(profile)
+    }
+
+    /// builtin returns the profile built into rnb with the name `name`, e.g. `ebpaj` for the
+    /// conventions of the EPUB 3 production guide of the Electronic Book Publishers Association
+    /// of Japan (電書協).
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (_, toml) = BUILTIN_PROFILES
+            .iter()
+            .find(|&&(builtin, _)| builtin == name)?;
+
+        // The built-in profiles are tested to be valid
+        Self::parse(toml).ok()
+    }
+
+    /// builtin_names returns the names of the profiles built into rnb.
+    pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+        BUILTIN_PROFILES.iter().map(|&(name, _)| name)
+    }
+
+    /// extend adds the conventions of `other` to this profile. Its class names take precedence
+    /// over the ones which this profile already has.
+    pub fn extend(&mut self, other: Self) {
+        self.classes.extend(other.classes);
+        self.gaiji_classes.extend(other.gaiji_classes);
+    }
+
+    /// class_style returns the style that the class names in `class` stand for, with later class
+    /// names taking precedence.
+    pub(super) fn class_style(&self, class: &[u8]) -> Style {
+        if self.classes.is_empty() {
+            return Style::default();
+        }
+
+        class
+            .split(|&c| c == b' ')
+            .filter_map(|name| self.classes.get(str::from_utf8(name).ok()?))
+            .fold(Style::default(), |style, &class_style| {
+                style.with(class_style)
+            })
+    }
+
+    /// is_gaiji_class returns whether `name` is the class name of an `<img>` of a 外字.
+    pub(super) fn is_gaiji_class(&self, name: &[u8]) -> bool {
+        name == b"gaiji"
+            || self
+                .gaiji_classes
+                .iter()
+                .any(|class| class.as_bytes() == name)
+    }
+}
+
+/// ProfileError is a reason that a profile couldn't be parsed, along with the line where it
+/// occurred.
+#[derive(Debug, PartialEq)]
+pub struct ProfileError {
+    pub line: usize,
+    pub kind: ProfileErrorKind,
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+#[derive(Debug, PartialEq)]
+pub enum ProfileErrorKind {
+    /// A line which isn't a table header, a key with a value or a comment.
+    Syntax,
+    UnknownTable {
+        name: String,
+    },
+    UnknownKey {
+        key: String,
+    },
+    /// A value of the wrong type, or CSS declarations which don't set any style that rnb keeps.
+    InvalidValue {
+        key: String,
+    },
+}
+
+impl fmt::Display for ProfileErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax => write!(f, "expected a [table], a key = value pair or a # comment"),
+            Self::UnknownTable { name } => write!(f, "unknown table [{name}]"),
+            Self::UnknownKey { key } => write!(f, "unknown key {key}"),
+            Self::InvalidValue { key } => write!(f, "invalid value for {key}"),
+        }
+    }
+}
+
+/// is_comment returns whether the rest of a line after a value is empty or a comment.
+fn is_comment(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// parse_key returns the key at the start of a line, which is either bare (e.g. `k-bold`) or
+/// quoted (e.g. `"太字"`), along with the rest of the line.
+fn parse_key(line: &str) -> Option<(String, &str)> {
+    if line.starts_with(['"', '\'']) {
+        return parse_string(line);
+    }
+
+    let end = line
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(line.len());
+    if end == 0 {
+        return None;
+    }
+
+    Some((line[..end].to_string(), &line[end..]))
+}
+
+/// parse_string returns the value of the basic (`"..."`) or literal (`'...'`) string at the start
+/// of `s`, along with the rest of `s`.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    if let Some(literal) = s.strip_prefix('\'') {
+        let (value, rest) = literal.split_once('\'')?;
+        return Some((value.to_string(), rest));
+    }
+
+    let mut value = String::new();
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[1 + i + 1..])),
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                c @ ('"' | '\\') => value.push(c),
+                _ => return None,
+            },
+            c => value.push(c),
+        }
+    }
+
+    None
+}
+
+/// parse_array returns the strings of the array of strings at the start of `s`, e.g.
+/// `["a", "b"]`, along with the rest of `s`.
+fn parse_array(s: &str) -> Option<(Vec<String>, &str)> {
+    let mut values = Vec::new();
+    let mut rest = s.strip_prefix('[')?.trim_start();
+
+    loop {
+        if let Some(after) = rest.strip_prefix(']') {
+            return Some((values, after));
+        }
+
+        let (value, after) = parse_string(rest)?;
+        values.push(value);
+
+        rest = after.trim_start();
+        match rest.strip_prefix(',') {
+            Some(after) => rest = after.trim_start(),
+            None if rest.starts_with(']') => {}
+            None => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EmphasisStyle, epub::style::FontSize};
+
+    #[test]
+    fn parse_profile() {
+        let profile = Profile::parse(
+            "# A profile\n\
+             gaiji-classes = [\"gaiji-line\", 'gaiji-wide',] # trailing comma\n\
+             \n\
+             [classes]\n\
+             gfont = \"font-size: 120%\"\n\
+             \"太字\" = 'font-weight: bold'\n",
+        )
+        .unwrap();
+
+        assert!(profile.is_gaiji_class(b"gaiji"));
+        assert!(profile.is_gaiji_class(b"gaiji-wide"));
+        assert!(!profile.is_gaiji_class(b"gaiji-"));
+        assert_eq!(
+            profile.class_style("太字 x gfont".as_bytes()),
+            Style {
+                bold: Some(true),
+                font_size: Some(FontSize::Larger),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let error = |toml: &str| Profile::parse(toml).unwrap_err();
+
+        assert_eq!(
+            error("[classes]\ngfont = \"font-family: gothic\""),
+            ProfileError {
+                line: 2,
+                kind: ProfileErrorKind::InvalidValue {
+                    key: "gfont".to_string(),
+                },
+            },
+        );
+        assert_eq!(
+            error("gaiji-classes = [\"a\" \"b\"]").kind,
+            ProfileErrorKind::InvalidValue {
+                key: "gaiji-classes".to_string(),
+            },
+        );
+        assert_eq!(
+            error("[styles]").kind,
+            ProfileErrorKind::UnknownTable {
+                name: "styles".to_string(),
+            },
+        );
+        assert_eq!(
+            error("gfont = \"font-weight: bold\"").kind,
+            ProfileErrorKind::UnknownKey {
+                key: "gfont".to_string(),
+            },
+        );
+        assert_eq!(error("= 1").kind, ProfileErrorKind::Syntax);
+    }
+
+    #[test]
+    fn builtin_profiles_are_valid() {
+        for (name, toml) in BUILTIN_PROFILES {
+            assert!(Profile::parse(toml).is_ok(), "{name}");
+        }
+        assert!(Profile::builtin("ebpaj").is_some());
+        assert_eq!(
+            Profile::builtin("aozora")
+                .unwrap()
+                .class_style(b"futoji sesame_dot"),
+            Style {
+                bold: Some(true),
+                emphasis: Some(Some(EmphasisStyle::FilledSesame)),
+                ..Default::default()
+            },
+        );
+        assert!(Profile::builtin("unknown").is_none());
+    }
+}
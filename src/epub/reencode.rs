@@ -0,0 +1,312 @@
+//! Re-encoding of images to make them smaller, e.g. for e-ink devices with small grayscale
+//! screens.
+
+use crate::{Image, ImageFormat};
+use image::{
+    AnimationDecoder, DynamicImage, ImageEncoder,
+    codecs::{
+        gif::GifDecoder,
+        jpeg::JpegEncoder,
+        png::{self, PngDecoder, PngEncoder},
+        webp::WebPDecoder,
+    },
+    imageops::FilterType,
+};
+use std::io::Cursor;
+
+/// ImageOptions control how images are re-encoded. With the default options, images are stored as
+/// they are in the EPUB.
+#[derive(Clone, Debug, Default)]
+pub struct ImageOptions {
+    /// max_width and max_height are the largest size of images in pixels. Larger images are scaled
+    /// down to fit, keeping their aspect ratio.
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// grayscale controls whether colour images are converted to grayscale.
+    pub grayscale: bool,
+    /// jpeg_quality is the quality (1-100) that JPEG images are recompressed with. Images which
+    /// are re-encoded for other reasons use [`DEFAULT_JPEG_QUALITY`] if it's not given.
+    pub jpeg_quality: Option<u8>,
+    /// optimize_png controls whether PNG images are recompressed with the best compression.
+    pub optimize_png: bool,
+}
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+impl ImageOptions {
+    fn is_default(&self) -> bool {
+        self.max_width.is_none()
+            && self.max_height.is_none()
+            && !self.grayscale
+            && self.jpeg_quality.is_none()
+            && !self.optimize_png
+    }
+}
+
+/// reencode returns `image` transformed according to `options`, or `None` when it's better kept
+/// as it is. That's the case for images which can't be decoded, SVG, AVIF and animated images,
+/// and images which re-encoding wouldn't make smaller without scaling them down.
+///
+/// JPEG images, and lossy WebP images without transparency, are re-encoded as JPEG. Other images
+/// are re-encoded as PNG.
+pub(super) fn reencode(image: &Image, options: &ImageOptions) -> Option<Image> {
+    if options.is_default() {
+        return None;
+    }
+
+    let data = &*image.data;
+    let (decoded_format, output) = match image.format {
+        ImageFormat::Jpeg => (image::ImageFormat::Jpeg, ImageFormat::Jpeg),
+        ImageFormat::Png if !is_apng(data)? => (image::ImageFormat::Png, ImageFormat::Png),
+        ImageFormat::Gif if !is_animated_gif(data)? => (image::ImageFormat::Gif, ImageFormat::Png),
+        ImageFormat::WebP if !is_animated_webp(data)? => {
+            let output = if is_lossy_webp(data) {
+                ImageFormat::Jpeg
+            } else {
+                ImageFormat::Png
+            };
+            (image::ImageFormat::WebP, output)
+        }
+        _ => return None,
+    };
+
+    let mut decoded = image::load_from_memory_with_format(data, decoded_format).ok()?;
+
+    let max_width = options.max_width.unwrap_or(u32::MAX);
+    let max_height = options.max_height.unwrap_or(u32::MAX);
+    let scaled = decoded.width() > max_width || decoded.height() > max_height;
+    if scaled {
+        decoded = decoded.resize(max_width, max_height, FilterType::CatmullRom);
+    }
+
+    let grayscaled = options.grayscale && decoded.color().has_color();
+    if grayscaled {
+        decoded = decoded.grayscale();
+    }
+
+    let recompressed = match image.format {
+        ImageFormat::Jpeg => options.jpeg_quality.is_some(),
+        ImageFormat::Png => options.optimize_png,
+        _ => false,
+    };
+    if !scaled && !grayscaled && !recompressed {
+        return None;
+    }
+
+    let encoded = match output {
+        ImageFormat::Jpeg => encode_jpeg(&decoded, options.jpeg_quality),
+        _ => encode_png(&decoded, options.optimize_png),
+    }?;
+
+    // Images which are too large are scaled down even if that makes them use more space, since
+    // devices might not be able to show them otherwise.
+    if !scaled && encoded.len() >= data.len() {
+        return None;
+    }
+
+    Some(Image {
+        data: encoded.into_boxed_slice(),
+        format: output,
+        width: decoded.width(),
+        height: decoded.height(),
+    })
+}
+
+fn encode_jpeg(decoded: &DynamicImage, quality: Option<u8>) -> Option<Vec<u8>> {
+    // JPEG doesn't support transparency, or more than 8 bits per channel
+    let decoded = if decoded.color().has_color() {
+        DynamicImage::ImageRgb8(decoded.to_rgb8())
+    } else {
+        DynamicImage::ImageLuma8(decoded.to_luma8())
+    };
+
+    let mut buf = Vec::new();
+    JpegEncoder::new_with_quality(&mut buf, quality.unwrap_or(DEFAULT_JPEG_QUALITY))
+        .write_image(
+            decoded.as_bytes(),
+            decoded.width(),
+            decoded.height(),
+            decoded.color().into(),
+        )
+        .ok()?;
+
+    Some(buf)
+}
+
+fn encode_png(decoded: &DynamicImage, optimize: bool) -> Option<Vec<u8>> {
+    let compression = if optimize {
+        png::CompressionType::Best
+    } else {
+        png::CompressionType::Default
+    };
+
+    let mut buf = Vec::new();
+    PngEncoder::new_with_quality(&mut buf, compression, png::FilterType::Adaptive)
+        .write_image(
+            decoded.as_bytes(),
+            decoded.width(),
+            decoded.height(),
+            decoded.color().into(),
+        )
+        .ok()?;
+
+    Some(buf)
+}
+
+/// is_apng returns whether a PNG image is animated, or `None` if it can't be decoded.
+fn is_apng(data: &[u8]) -> Option<bool> {
+    PngDecoder::new(Cursor::new(data)).ok()?.is_apng().ok()
+}
+
+/// is_animated_gif returns whether a GIF image has more than one frame, or `None` if it can't be
+/// decoded.
+fn is_animated_gif(data: &[u8]) -> Option<bool> {
+    let frames = GifDecoder::new(Cursor::new(data)).ok()?.into_frames();
+    Some(frames.take(2).count() > 1)
+}
+
+/// is_animated_webp returns whether a WebP image is animated, or `None` if it can't be decoded.
+fn is_animated_webp(data: &[u8]) -> Option<bool> {
+    Some(WebPDecoder::new(Cursor::new(data)).ok()?.has_animation())
+}
+
+/// is_lossy_webp returns whether a WebP image is in the simple lossy format, which doesn't have
+/// transparency.
+fn is_lossy_webp(data: &[u8]) -> bool {
+    data.get(12..16) == Some(b"VP8 ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, RgbImage, RgbaImage};
+
+    fn png(image: DynamicImage) -> Image {
+        Image {
+            format: ImageFormat::Png,
+            width: image.width(),
+            height: image.height(),
+            data: encode_png(&image, false).unwrap().into_boxed_slice(),
+        }
+    }
+
+    fn jpeg(image: DynamicImage) -> Image {
+        Image {
+            format: ImageFormat::Jpeg,
+            width: image.width(),
+            height: image.height(),
+            data: encode_jpeg(&image, Some(100)).unwrap().into_boxed_slice(),
+        }
+    }
+
+    /// noise returns an image which doesn't compress well, so that re-encoding it can make it
+    /// smaller.
+    fn noise(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let v = (x * 7919 + y * 104_729) ^ (x * y);
+            image::Rgb([v as u8, (v >> 8) as u8, (v >> 3) as u8])
+        })
+    }
+
+    #[test]
+    fn default_options_keep_images() {
+        let image = jpeg(DynamicImage::ImageRgb8(noise(64, 64)));
+
+        assert_eq!(reencode(&image, &ImageOptions::default()), None);
+    }
+
+    #[test]
+    fn scale_down_to_fit() {
+        let image = png(DynamicImage::ImageRgba8(RgbaImage::new(400, 100)));
+        let options = ImageOptions {
+            max_width: Some(100),
+            max_height: Some(100),
+            ..Default::default()
+        };
+
+        let reencoded = reencode(&image, &options).unwrap();
+
+        assert_eq!(reencoded.format, ImageFormat::Png);
+        assert_eq!((reencoded.width, reencoded.height), (100, 25));
+        let decoded = image::load_from_memory(&reencoded.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (100, 25));
+    }
+
+    #[test]
+    fn small_images_are_not_scaled() {
+        let image = png(DynamicImage::ImageRgba8(RgbaImage::new(40, 10)));
+        let options = ImageOptions {
+            max_width: Some(100),
+            ..Default::default()
+        };
+
+        assert_eq!(reencode(&image, &options), None);
+    }
+
+    #[test]
+    fn grayscale_and_recompress_jpeg() {
+        let image = jpeg(DynamicImage::ImageRgb8(noise(64, 64)));
+        let options = ImageOptions {
+            grayscale: true,
+            jpeg_quality: Some(50),
+            ..Default::default()
+        };
+
+        let reencoded = reencode(&image, &options).unwrap();
+
+        assert_eq!(reencoded.format, ImageFormat::Jpeg);
+        assert_eq!((reencoded.width, reencoded.height), (64, 64));
+        assert!(reencoded.data.len() < image.data.len());
+        let decoded = image::load_from_memory(&reencoded.data).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+    }
+
+    #[test]
+    fn keep_original_when_smaller() {
+        let image = png(DynamicImage::ImageLuma8(GrayImage::new(64, 64)));
+        let options = ImageOptions {
+            grayscale: true,
+            ..Default::default()
+        };
+
+        // The image is already grayscale, so there's nothing to do
+        assert_eq!(reencode(&image, &options), None);
+    }
+
+    #[test]
+    fn keep_scaled_image_when_larger() {
+        let decoded = DynamicImage::ImageRgb8(noise(64, 64));
+        let image = Image {
+            data: encode_jpeg(&decoded, Some(10)).unwrap().into_boxed_slice(),
+            format: ImageFormat::Jpeg,
+            width: 64,
+            height: 64,
+        };
+        let options = ImageOptions {
+            max_width: Some(60),
+            ..Default::default()
+        };
+
+        let reencoded = reencode(&image, &options).unwrap();
+
+        // The default quality is higher than the quality of the original
+        assert!(reencoded.data.len() > image.data.len());
+        assert_eq!((reencoded.width, reencoded.height), (60, 60));
+    }
+
+    #[test]
+    fn undecodable_images_are_kept() {
+        let image = Image {
+            data: Box::new([0xff, 0xd8, 0xff, 0]),
+            format: ImageFormat::Jpeg,
+            width: 0,
+            height: 0,
+        };
+        let options = ImageOptions {
+            max_width: Some(1),
+            ..Default::default()
+        };
+
+        assert_eq!(reencode(&image, &options), None);
+    }
+}
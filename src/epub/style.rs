@@ -0,0 +1,509 @@
+//! Parsing of the CSS of a book, for the formatting that the `.rnb` format keeps.
+
+use crate::EmphasisStyle;
+
+/// Stylesheet is the rules of a CSS file which can be matched against elements. Only rules with
+/// simple selectors are kept: an element name, a class or both (e.g. `p`, `.center` or
+/// `p.center`). Rules within at-rules like `@media` are left out.
+#[derive(Debug, Default)]
+pub(super) struct Stylesheet {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// element is the lowercase name of the element that the rule applies to, if it's limited to
+    /// one.
+    element: Option<Box<str>>,
+    class: Option<Box<str>>,
+    style: Style,
+}
+
+impl Stylesheet {
+    /// matching_rules returns the rules of the stylesheet which apply to an element with the name
+    /// `name` and the class names in `class`, in the order that they appear.
+    fn matching_rules<'a>(
+        &'a self,
+        name: &'a [u8],
+        class: Option<&'a [u8]>,
+    ) -> impl Iterator<Item = &'a Rule> {
+        self.rules.iter().filter(move |rule| {
+            rule.element
+                .as_deref()
+                .is_none_or(|element| element.as_bytes().eq_ignore_ascii_case(name))
+                && rule.class.as_deref().is_none_or(|rule_class| {
+                    class.is_some_and(|class| {
+                        class
+                            .split(|&c| c == b' ')
+                            .any(|name| name == rule_class.as_bytes())
+                    })
+                })
+        })
+    }
+}
+
+/// cascade returns the style that the rules of `stylesheets` give an element with the name `name`
+/// and the class names in `class`. Rules with a class take precedence over rules without one, and
+/// otherwise later rules take precedence over earlier ones, including the rules of later
+/// stylesheets.
+pub(super) fn cascade(stylesheets: &[&Stylesheet], name: &[u8], class: Option<&[u8]>) -> Style {
+    let mut rules = stylesheets
+        .iter()
+        .flat_map(|stylesheet| stylesheet.matching_rules(name, class))
+        .collect::<Vec<_>>();
+    // The sort is stable, so rules which are as specific as each other stay in order
+    rules.sort_by_key(|rule| (rule.class.is_some(), rule.element.is_some()));
+
+    rules
+        .into_iter()
+        .fold(Style::default(), |style, rule| style.with(rule.style))
+}
+
+/// Style is the formatting of an element which the `.rnb` format keeps. Properties are `None` when
+/// they aren't set.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(super) struct Style {
+    pub(super) bold: Option<bool>,
+    pub(super) italic: Option<bool>,
+    pub(super) font_size: Option<FontSize>,
+    pub(super) text_align: Option<TextAlign>,
+    pub(super) text_indent: Option<TextIndent>,
+    pub(super) writing_mode: Option<WritingMode>,
+    /// emphasis is `Some(None)` for `text-emphasis-style: none`.
+    pub(super) emphasis: Option<Option<EmphasisStyle>>,
+    /// upright is whether the text is set horizontally within vertical text (縦中横).
+    pub(super) upright: Option<bool>,
+}
+
+/// FontSize is the size of text compared to the text around it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum FontSize {
+    Normal,
+    Larger,
+    Smaller,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum TextAlign {
+    Start,
+    Center,
+    /// End is the right of horizontal text, or the bottom of vertical text.
+    End,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum TextIndent {
+    None,
+    /// Indent is a positive `text-indent`, which indents the first line.
+    Indent,
+    /// Hanging is a negative `text-indent`, which is used with padding to indent the lines after
+    /// the first one.
+    Hanging,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum WritingMode {
+    Horizontal,
+    Vertical,
+}
+
+impl Style {
+    /// parse returns the style that the declarations in `css` set, e.g. the contents of a `style`
+    /// attribute. Properties which aren't kept and values which aren't understood are left out.
+    pub(super) fn parse(css: &str) -> Self {
+        let mut style = Self::default();
+        for (property, value) in declarations(css) {
+            style.set(&property, value);
+        }
+
+        style
+    }
+
+    fn set(&mut self, property: &str, value: &str) {
+        let value = value.trim();
+        let value = value
+            .strip_suffix("!important")
+            .unwrap_or(value)
+            .trim()
+            .to_ascii_lowercase();
+
+        match property {
+            "font-weight" => self.bold = parse_font_weight(&value).or(self.bold),
+            "font-style" => {
+                self.italic = match value.as_str() {
+                    "normal" => Some(false),
+                    _ if value == "italic" || value.starts_with("oblique") => Some(true),
+                    _ => self.italic,
+                };
+            }
+            "font-size" => self.font_size = parse_font_size(&value).or(self.font_size),
+            "text-align" => {
+                self.text_align = match value.as_str() {
+                    "left" | "start" | "justify" => Some(TextAlign::Start),
+                    "center" => Some(TextAlign::Center),
+                    "right" | "end" => Some(TextAlign::End),
+                    _ => self.text_align,
+                };
+            }
+            "text-indent" => self.text_indent = parse_text_indent(&value).or(self.text_indent),
+            "writing-mode" => {
+                self.writing_mode = match value.as_str() {
+                    "horizontal-tb" | "lr" | "lr-tb" | "rl" | "rl-tb" => {
+                        Some(WritingMode::Horizontal)
+                    }
+                    "vertical-rl" | "vertical-lr" | "sideways-rl" | "sideways-lr" | "tb"
+                    | "tb-rl" | "tb-lr" => Some(WritingMode::Vertical),
+                    _ => self.writing_mode,
+                };
+            }
+            "text-emphasis-style" | "text-emphasis" => {
+                if value
+                    .split_ascii_whitespace()
+                    .any(|keyword| keyword == "none")
+                {
+                    self.emphasis = Some(None);
+                } else if let Some(emphasis) = parse_emphasis_style(&value) {
+                    self.emphasis = Some(Some(emphasis));
+                }
+            }
+            // e.g. `all` or `digits 2`
+            "text-combine-upright" => self.upright = Some(value != "none"),
+            // The names before it was standardised, with the values `horizontal` or `none`
+            "text-combine" | "text-combine-horizontal" => {
+                self.upright = Some(value == "horizontal");
+            }
+            _ => {}
+        }
+    }
+
+    /// with returns this style with the properties that `other` sets replacing its own.
+    pub(super) fn with(self, other: Self) -> Self {
+        Self {
+            bold: other.bold.or(self.bold),
+            italic: other.italic.or(self.italic),
+            font_size: other.font_size.or(self.font_size),
+            text_align: other.text_align.or(self.text_align),
+            text_indent: other.text_indent.or(self.text_indent),
+            writing_mode: other.writing_mode.or(self.writing_mode),
+            emphasis: other.emphasis.or(self.emphasis),
+            upright: other.upright.or(self.upright),
+        }
+    }
+
+    /// inherited_by returns the style of a child element of an element with this style, which
+    /// sets the properties of `declared` itself. `text-combine-upright` is the only property
+    /// which isn't inherited.
+    pub(super) fn inherited_by(self, declared: Self) -> Self {
+        Self {
+            upright: declared.upright,
+            ..self.with(declared)
+        }
+    }
+}
+
+/// parse_stylesheet parses the rules of a CSS file. Rules that can't be matched and anything that
+/// can't be parsed are left out.
+pub(super) fn parse_stylesheet(css: &str) -> Stylesheet {
+    let css = without_comments(css);
+
+    let mut rules = Vec::new();
+    let mut rest = css.as_str();
+    while let Some(open) = rest.find('{') {
+        let Some(len) = block_len(&rest[open + 1..]) else {
+            break;
+        };
+        // Statements before the selector, e.g. `@charset "UTF-8";`, end with a semicolon
+        let prelude = rest[..open].rsplit(';').next().unwrap_or_default().trim();
+        let body = &rest[open + 1..open + 1 + len];
+        rest = &rest[open + 1 + len + 1..];
+
+        if prelude.starts_with('@') {
+            continue;
+        }
+
+        let style = Style::parse(body);
+        for selector in prelude.split(',') {
+            if let Some((element, class)) = parse_selector(selector.trim()) {
+                rules.push(Rule {
+                    element,
+                    class,
+                    style,
+                });
+            }
+        }
+    }
+
+    Stylesheet { rules }
+}
+
+/// without_comments returns `css` with its comments replaced by spaces.
+fn without_comments(css: &str) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        result.push_str(&rest[..start]);
+        result.push(' ');
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    result.push_str(rest);
+
+    result
+}
+
+/// block_len returns the length of the contents of a block, e.g. the declarations of a rule, which
+/// starts at the start of `css`. Blocks nested within it are skipped. It's `None` when the block
+/// isn't closed.
+fn block_len(css: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    for (i, c) in css.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+type Selector = (Option<Box<str>>, Option<Box<str>>);
+
+/// parse_selector returns the element name and class of a selector like `p`, `.center` or
+/// `p.center`. It's `None` for any other selector.
+fn parse_selector(selector: &str) -> Option<Selector> {
+    let (element, class) = match selector.split_once('.') {
+        Some((element, class)) => (element, Some(class)),
+        None => (selector, None),
+    };
+
+    let is_element = |s: &str| s.bytes().all(|c| c.is_ascii_alphanumeric());
+    let is_class = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii())
+    };
+    if !is_element(element)
+        || !class.is_none_or(is_class)
+        || (element.is_empty() && class.is_none())
+    {
+        return None;
+    }
+
+    Some((
+        (!element.is_empty()).then(|| element.to_ascii_lowercase().into()),
+        class.map(Into::into),
+    ))
+}
+
+/// declarations returns the properties and values of the declarations in `css`, e.g. the contents
+/// of a `style` attribute. Properties are lowercase and without the `-webkit-` and `-epub-`
+/// prefixes.
+fn declarations(css: &str) -> impl Iterator<Item = (String, &str)> {
+    css.split(';').filter_map(|declaration| {
+        let (property, value) = declaration.split_once(':')?;
+        let property = property.trim().to_ascii_lowercase();
+        let property = match property
+            .strip_prefix("-webkit-")
+            .or_else(|| property.strip_prefix("-epub-"))
+        {
+            Some(property) => property.to_string(),
+            None => property,
+        };
+
+        Some((property, value))
+    })
+}
+
+/// parse_length returns the number at the start of a CSS length, e.g. `1.5em`, along with its
+/// unit.
+fn parse_length(value: &str) -> Option<(f32, &str)> {
+    let unit_start = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+')))
+        .unwrap_or(value.len());
+    let number = value[..unit_start].parse().ok()?;
+
+    Some((number, value[unit_start..].trim()))
+}
+
+fn parse_font_weight(value: &str) -> Option<bool> {
+    match value {
+        "bold" | "bolder" => Some(true),
+        "normal" | "lighter" => Some(false),
+        _ => value.parse::<u16>().ok().map(|weight| weight >= 600),
+    }
+}
+
+/// parse_font_size returns the size of text with the `font-size` `value` compared to the text
+/// around it. Sizes which are the same as the text around it, like `100%`, are `None`, as are
+/// sizes in absolute units.
+fn parse_font_size(value: &str) -> Option<FontSize> {
+    match value {
+        "medium" => return Some(FontSize::Normal),
+        "larger" | "large" | "x-large" | "xx-large" | "xxx-large" => return Some(FontSize::Larger),
+        "smaller" | "small" | "x-small" | "xx-small" => return Some(FontSize::Smaller),
+        _ => {}
+    }
+
+    let (number, unit) = parse_length(value)?;
+    let ratio = match unit {
+        "%" => number / 100.0,
+        "em" | "rem" => number,
+        _ => return None,
+    };
+
+    if ratio > 1.0 {
+        Some(FontSize::Larger)
+    } else if ratio < 1.0 {
+        Some(FontSize::Smaller)
+    } else if unit == "rem" {
+        // The size of the text of the root element
+        Some(FontSize::Normal)
+    } else {
+        None
+    }
+}
+
+fn parse_text_indent(value: &str) -> Option<TextIndent> {
+    let mut keywords = value.split_ascii_whitespace();
+    let (number, _) = parse_length(keywords.next()?)?;
+    if keywords.any(|keyword| keyword == "hanging") {
+        return Some(TextIndent::Hanging);
+    }
+
+    Some(if number > 0.0 {
+        TextIndent::Indent
+    } else if number < 0.0 {
+        TextIndent::Hanging
+    } else {
+        TextIndent::None
+    })
+}
+
+/// parse_emphasis_style parses the value of the CSS `text-emphasis-style` property, or the
+/// `text-emphasis` shorthand. Marks which are a custom string aren't supported.
+fn parse_emphasis_style(value: &str) -> Option<EmphasisStyle> {
+    let mut open = None;
+    let mut shape = None;
+    for keyword in value.split_ascii_whitespace() {
+        match keyword {
+            "filled" => open = Some(false),
+            "open" => open = Some(true),
+            "none" => return None,
+            "sesame" | "dot" | "circle" | "double-circle" | "triangle" => shape = Some(keyword),
+            // e.g. the colour in the shorthand
+            _ => {}
+        }
+    }
+
+    match (shape, open) {
+        (Some(shape), open) => emphasis_shape(shape.as_bytes(), open.unwrap_or(false)),
+        // Sesame is the default shape for vertical text, which Japanese books mostly are
+        (None, Some(open)) => emphasis_shape(b"sesame", open),
+        (None, None) => None,
+    }
+}
+
+/// emphasis_shape returns the style of emphasis marks with the CSS name `shape`, e.g.
+/// `double-circle`.
+pub(super) fn emphasis_shape(shape: &[u8], open: bool) -> Option<EmphasisStyle> {
+    let (filled, open_style) = match shape {
+        b"sesame" => (EmphasisStyle::FilledSesame, EmphasisStyle::OpenSesame),
+        b"dot" => (EmphasisStyle::FilledDot, EmphasisStyle::OpenDot),
+        b"circle" => (EmphasisStyle::FilledCircle, EmphasisStyle::OpenCircle),
+        b"double-circle" => (
+            EmphasisStyle::FilledDoubleCircle,
+            EmphasisStyle::OpenDoubleCircle,
+        ),
+        b"triangle" => (EmphasisStyle::FilledTriangle, EmphasisStyle::OpenTriangle),
+        _ => return None,
+    };
+
+    Some(if open { open_style } else { filled })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let stylesheet = parse_stylesheet(
+            "@charset \"UTF-8\";\n\
+             /* p { font-weight: bold; } */\n\
+             html { -epub-writing-mode: vertical-rl; }\n\
+             p { text-indent: 1em; }\n\
+             .gothic, p.no-indent, div p, #id, a:hover { text-indent: 0 }\n\
+             @media amzn-kf8 { .gothic { font-weight: bold; } }\n\
+             .midashi { font-weight: BOLD !important; font-size: 120%; text-align: center }\n\
+             .em-dot { -webkit-text-emphasis-style: dot; }",
+        );
+
+        let style_of = |name: &[u8], class: Option<&[u8]>| cascade(&[&stylesheet], name, class);
+
+        assert_eq!(
+            style_of(b"html", None),
+            Style {
+                writing_mode: Some(WritingMode::Vertical),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            style_of(b"p", Some(b"a")),
+            Style {
+                text_indent: Some(TextIndent::Indent),
+                ..Default::default()
+            },
+        );
+        // Rules with a class take precedence, and rules within @media are left out
+        assert_eq!(
+            style_of(b"P", Some(b"gothic")),
+            Style {
+                text_indent: Some(TextIndent::None),
+                ..Default::default()
+            },
+        );
+        assert_eq!(style_of(b"div", Some(b"no-indent")), Style::default());
+        assert_eq!(
+            style_of(b"p", Some(b"midashi em-dot")),
+            Style {
+                bold: Some(true),
+                font_size: Some(FontSize::Larger),
+                text_align: Some(TextAlign::Center),
+                text_indent: Some(TextIndent::Indent),
+                emphasis: Some(Some(EmphasisStyle::FilledDot)),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_declarations() {
+        assert_eq!(
+            Style::parse(
+                "font-size: 0.8em; font-weight: 400; font-style: oblique 10deg; \
+                 text-indent: -1em; text-emphasis: none; -webkit-text-combine: horizontal; \
+                 writing-mode: unknown"
+            ),
+            Style {
+                bold: Some(false),
+                italic: Some(true),
+                font_size: Some(FontSize::Smaller),
+                text_indent: Some(TextIndent::Hanging),
+                emphasis: Some(None),
+                upright: Some(true),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            Style::parse("font-size: 100%; text-emphasis: open red"),
+            Style {
+                emphasis: Some(Some(EmphasisStyle::OpenSesame)),
+                ..Default::default()
+            },
+        );
+    }
+}
@@ -0,0 +1,2459 @@
+//! Parsing of the XHTML text files of a book into paragraphs, and merging them into blocks.
+
+use super::{
+    Gaiji, ImageFiles, Stylesheets, TocItem, get_attr,
+    package::resolve_href,
+    profile::Profile,
+    style::{
+        FontSize, Style, Stylesheet, TextAlign, TextIndent, WritingMode, cascade, emphasis_shape,
+    },
+    text_str, unescape,
+};
+use crate::{ContentBlock, Emphasis, EmphasisStyle, Ruby, SpanStyle, StyleSpan};
+use quick_xml::{
+    Reader,
+    events::{BytesStart, attributes::Attributes},
+};
+use std::{borrow::Cow, fmt, mem, ops::Range};
+
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct Paragraph {
+    /// text is empty when the paragraph is an image
+    text: Vec<u16>,
+    image_idx: Option<u32>,
+    ruby: Vec<Ruby>,
+    /// secondary_ruby are the annotations from `<rtc>`.
+    secondary_ruby: Vec<Ruby>,
+    spans: Vec<StyleSpan>,
+    emphasis: Vec<Emphasis>,
+    /// flags indicate paragraph-level formatting information, like the flags of a
+    /// [`ContentBlock::Text`].
+    /// Third-lowest bit is set for the pieces after the first of a paragraph which was split.
+    flags: u8,
+    /// heading_level is the level of a heading (`<h1>` to `<h6>`), or 0 for other text.
+    heading_level: u8,
+    /// starts_section is true when an entry in the table of contents points to this paragraph.
+    /// These paragraphs always start a new block.
+    starts_section: bool,
+}
+
+impl Paragraph {
+    fn into_block(self) -> ContentBlock {
+        ContentBlock::Text {
+            text: self.text.into_boxed_slice(),
+            ruby: self.ruby.into_boxed_slice(),
+            secondary_ruby: self.secondary_ruby.into_boxed_slice(),
+            spans: self.spans.into_boxed_slice(),
+            emphasis: self.emphasis.into_boxed_slice(),
+            flags: self.flags,
+            heading_level: self.heading_level,
+        }
+    }
+
+    /// is_formatted returns whether the paragraph has formatting which prevents merging it with
+    /// other paragraphs. Paragraphs which are only laid out differently (e.g. centred) can be
+    /// merged with paragraphs which are laid out the same way.
+    fn is_formatted(&self) -> bool {
+        self.flags & 0b11 != 0 || self.heading_level != 0
+    }
+
+    /// layout_flags returns the flags of the paragraph which describe how it's laid out, e.g. its
+    /// alignment.
+    fn layout_flags(&self) -> u8 {
+        self.flags & !0b111
+    }
+}
+
+/// ParsedText is the content of a single text file.
+#[derive(Debug, Default)]
+pub(super) struct ParsedText {
+    paragraphs: Vec<Paragraph>,
+    anchors: Vec<Anchor>,
+    /// warnings are the problems which were worked around when parsing leniently.
+    pub(super) warnings: Vec<TextError>,
+}
+
+/// Anchor is an element with an `id` which can be linked to, e.g. from the table of contents.
+#[derive(Debug, PartialEq)]
+struct Anchor {
+    id: Box<str>,
+    /// paragraph_idx is the index of the first paragraph at or after the element.
+    paragraph_idx: usize,
+}
+
+/// TextError is an error in the content of a text file, along with where it occurred.
+#[derive(Debug)]
+pub struct TextError {
+    pub line: usize,
+    pub position: u64,
+    pub kind: TextErrorKind,
+}
+
+impl TextError {
+    fn at(content: &str, position: u64, kind: TextErrorKind) -> Self {
+        let end = usize::try_from(position)
+            .unwrap_or(usize::MAX)
+            .min(content.len());
+        let line = content.as_bytes()[..end]
+            .iter()
+            .filter(|&&c| c == b'\n')
+            .count()
+            + 1;
+
+        Self {
+            line,
+            position,
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (byte {}): {}", self.line, self.position, self.kind)
+    }
+}
+
+/// ErrorMode controls what happens when a text file contains something that can't be converted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ErrorMode {
+    /// Stop at the first problem.
+    #[default]
+    Strict,
+    /// Record the problem as a warning, and fall back to something sensible, e.g. the alt text of
+    /// an image which isn't in the book.
+    Lenient,
+}
+
+impl ErrorMode {
+    /// recover returns `error` in strict mode. Otherwise, it's added to `warnings` and the caller
+    /// should continue with a fallback.
+    fn recover(self, error: TextError, warnings: &mut Vec<TextError>) -> Result<(), TextError> {
+        match self {
+            Self::Strict => Err(error),
+            Self::Lenient => {
+                warnings.push(error);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TextErrorKind {
+    Xml(quick_xml::Error),
+    /// An `<img>` without a `src`.
+    MissingSrc {
+        element: String,
+    },
+    /// An `<img>` or `<image>` which doesn't point to an image in the book.
+    UnknownImage {
+        element: String,
+    },
+    /// A gaiji image which doesn't have a replacement in gaiji.json.
+    MissingGaiji {
+        src: String,
+    },
+    CData,
+    /// Ruby which is longer than what can be encoded.
+    RubyTooLong {
+        element: String,
+    },
+}
+
+impl fmt::Display for TextErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Xml(error) => error.fmt(f),
+            Self::MissingSrc { element } => write!(f, "<img> doesn't have a src: {element}"),
+            Self::UnknownImage { element } => {
+                write!(f, "doesn't point to a valid image: {element}")
+            }
+            Self::MissingGaiji { src } => write!(f, "failed to find mapping for gaiji {src}"),
+            Self::CData => write!(f, "unhandled CDATA section"),
+            Self::RubyTooLong { element } => write!(f, "ruby is too long to encode: {element}"),
+        }
+    }
+}
+
+/// flatten_text joins together the paragraphs of all the text files, and finds the index of the
+/// paragraph that each entry in `toc` points to. Entries which don't point into any of the text
+/// files are `None`.
+pub(super) fn flatten_text(
+    text: Vec<ParsedText>,
+    paths: &[String],
+    toc: &[TocItem],
+) -> (Vec<Paragraph>, Vec<Option<usize>>) {
+    let mut file_starts = Vec::with_capacity(text.len());
+    let mut num_paragraphs = 0;
+    for t in &text {
+        file_starts.push(num_paragraphs);
+        num_paragraphs += t.paragraphs.len();
+    }
+
+    let targets = toc
+        .iter()
+        .map(|item| {
+            let file_idx = paths.iter().position(|p| *p == item.path)?;
+
+            let paragraph_idx = item
+                .fragment
+                .as_deref()
+                .and_then(|fragment| {
+                    text[file_idx]
+                        .anchors
+                        .iter()
+                        .find(|anchor| &*anchor.id == fragment)
+                })
+                .map(|anchor| anchor.paragraph_idx)
+                .unwrap_or(0);
+
+            Some(file_starts[file_idx] + paragraph_idx)
+        })
+        .collect::<Vec<_>>();
+
+    let mut paragraphs = text
+        .into_iter()
+        .flat_map(|t| t.paragraphs)
+        .collect::<Vec<_>>();
+
+    for &target in targets.iter().flatten() {
+        if let Some(paragraph) = paragraphs.get_mut(target) {
+            paragraph.starts_section = true;
+        }
+    }
+
+    (paragraphs, targets)
+}
+
+/// GETA_MARK (〓) stands in for a gaiji which doesn't have a replacement.
+const GETA_MARK: u16 = 0x3013;
+
+/// BlockStyle is the formatting of an element whose text becomes paragraphs of its own.
+#[derive(Clone, Copy)]
+struct BlockStyle {
+    flags: u8,
+    heading_level: u8,
+}
+
+enum ParagraphParseState {
+    Content {
+        /// paragraph is the content of the paragraph so far.
+        paragraph: Paragraph,
+        /// keep_if_blank is true when the paragraph is added even if it's empty or whitespace,
+        /// which is the case for a `<p>` (e.g. a blank line) until an image within it splits its
+        /// text. Other elements often only contain whitespace between their child elements.
+        keep_if_blank: bool,
+    },
+    None,
+}
+
+impl ParagraphParseState {
+    fn new(style: BlockStyle, keep_if_blank: bool) -> Self {
+        Self::Content {
+            paragraph: Paragraph {
+                flags: style.flags,
+                heading_level: style.heading_level,
+                ..Default::default()
+            },
+            keep_if_blank,
+        }
+    }
+
+    /// text_len returns the length of the text of the current paragraph so far.
+    fn text_len(&self) -> usize {
+        match self {
+            Self::Content { paragraph, .. } => paragraph.text.len(),
+            Self::None => 0,
+        }
+    }
+}
+
+/// OpenInline is an inline element containing the current position, which formats the text
+/// within it.
+struct OpenInline {
+    styles: Vec<SpanStyle>,
+    emphasis: Option<EmphasisStyle>,
+    /// start is where the text of the element starts in the current paragraph.
+    start: usize,
+}
+
+/// is_text_block returns whether the text of an element becomes paragraphs of its own. Text
+/// directly within `<body>` is kept too.
+fn is_text_block(name: &[u8]) -> bool {
+    matches!(
+        name,
+        b"p" | b"div"
+            | b"li"
+            | b"blockquote"
+            | b"td"
+            | b"th"
+            | b"dt"
+            | b"dd"
+            | b"figcaption"
+            | b"body"
+    ) || heading_level(name) != 0
+}
+
+/// heading_level returns the level of a heading element, or 0 for other elements.
+fn heading_level(name: &[u8]) -> u8 {
+    match name {
+        [b'h', level @ b'1'..=b'6'] => level - b'0',
+        _ => 0,
+    }
+}
+
+/// is_non_text returns whether an element's text isn't part of the content of the book, although
+/// it can contain images.
+fn is_non_text(name: &[u8]) -> bool {
+    matches!(name, b"svg" | b"script" | b"style" | b"rp")
+}
+
+/// RubyParseState tracks the bases and readings of the `<ruby>` element containing the current
+/// position. Positions are offsets into the text of the current paragraph.
+struct RubyParseState {
+    /// start is where the base text of the whole `<ruby>` starts.
+    start: usize,
+    /// base_start is where the base text of the next reading starts when it doesn't have an `<rb>`.
+    base_start: usize,
+    /// bases are the base text that each `<rt>` applies to, in order. They come from `<rb>`s, or
+    /// the text before each `<rt>` when there aren't any.
+    bases: Vec<Range<usize>>,
+    num_readings: usize,
+    num_secondary_readings: usize,
+    in_rtc: bool,
+    /// reading is the text of the `<rt>` containing the current position, including the text of
+    /// elements nested within it.
+    reading: Option<Vec<u16>>,
+    /// rtc_text is the text directly within the current `<rtc>`, when it isn't split into `<rt>`s.
+    rtc_text: Vec<u16>,
+}
+
+impl RubyParseState {
+    fn new(start: usize) -> Self {
+        Self {
+            start,
+            base_start: start,
+            bases: Vec::new(),
+            num_readings: 0,
+            num_secondary_readings: 0,
+            in_rtc: false,
+            reading: None,
+            rtc_text: Vec::new(),
+        }
+    }
+
+    /// reading_base returns the base text of the `<rt>` which ended at `text_len`. Readings within
+    /// an `<rtc>` use the bases of the primary readings in the same order, or the whole base text
+    /// when there are more of them.
+    fn reading_base(&mut self, text_len: usize) -> Range<usize> {
+        if self.in_rtc {
+            let base = self.bases.get(self.num_secondary_readings).cloned();
+            self.num_secondary_readings += 1;
+            return base.unwrap_or(self.start..text_len);
+        }
+
+        let base = match self.bases.get(self.num_readings) {
+            Some(base) => base.clone(),
+            None => {
+                self.bases.push(self.base_start..text_len);
+                self.base_start..text_len
+            }
+        };
+        self.num_readings += 1;
+        self.base_start = text_len;
+
+        base
+    }
+}
+
+/// parse_text_file parses the paragraphs of a text file. `base_dir` is the directory within the
+/// archive which contains the file, which the paths of images and stylesheets are relative to.
+pub(super) fn parse_text_file(
+    content: &str,
+    base_dir: &str,
+    image_files: &ImageFiles,
+    gaiji: &Gaiji,
+    stylesheets: &Stylesheets,
+    profile: &Profile,
+    error_mode: ErrorMode,
+) -> Result<ParsedText, TextError> {
+    let mut reader = Reader::from_str(content);
+    let config = reader.config_mut();
+    config.expand_empty_elements = true;
+    config.check_end_names = false;
+    config.trim_markup_names_in_closing_tags = false;
+
+    let mut paragraphs = Vec::with_capacity(256);
+    let mut anchors = Vec::new();
+    let mut warnings = Vec::new();
+
+    let mut paragraph = ParagraphParseState::None;
+    let mut ruby_parse_state: Option<RubyParseState> = None;
+    let stylesheets = linked_stylesheets(content, base_dir, stylesheets);
+    // open_styles are the styles of the elements which contain the current position, including
+    // the styles that they inherit
+    let mut open_styles: Vec<Style> = Vec::new();
+    // open_blocks are the styles of the text block elements which contain the current position
+    let mut open_blocks = Vec::new();
+    // open_inlines are the inline elements with styles which contain the current position
+    let mut open_inlines = Vec::new();
+    // non_text_depth is the number of elements containing the current position whose text is
+    // left out
+    let mut non_text_depth = 0usize;
+
+    let mut buf = Vec::with_capacity(128);
+    loop {
+        let position = reader.buffer_position();
+        let error = |kind| TextError::at(content, position, kind);
+
+        match reader.read_event_into(&mut buf) {
+            Ok(quick_xml::events::Event::Start(e)) | Ok(quick_xml::events::Event::Empty(e)) => {
+                let parent_style = open_styles.last().copied().unwrap_or_default();
+                let element_style =
+                    parent_style.inherited_by(declared_style(&e, &stylesheets, profile));
+                open_styles.push(element_style);
+
+                // The text so far belongs to the element containing this one, so it has to be
+                // added before the anchors of this element
+                if is_text_block(e.name().as_ref()) {
+                    let style = BlockStyle {
+                        flags: block_flags(&element_style),
+                        heading_level: heading_level(e.name().as_ref()),
+                    };
+                    let keep_if_blank = e.name().as_ref() == b"p";
+                    split_inlines(&mut paragraph, &mut open_inlines);
+                    let previous = mem::replace(
+                        &mut paragraph,
+                        ParagraphParseState::new(style, keep_if_blank),
+                    );
+                    push_paragraph(&mut paragraphs, previous);
+                    open_blocks.push(style);
+                } else if is_inline_style(e.name().as_ref()) {
+                    let (styles, emphasis) = inline_spans(&parent_style, &element_style);
+                    open_inlines.push(OpenInline {
+                        styles,
+                        emphasis,
+                        start: paragraph.text_len(),
+                    });
+                } else if is_non_text(e.name().as_ref()) {
+                    non_text_depth += 1;
+                }
+
+                if let Some(id) = get_attr(e.attributes(), b"id") {
+                    anchors.push(Anchor {
+                        id: String::from_utf8_lossy(&id).into(),
+                        paragraph_idx: paragraphs.len(),
+                    });
+                }
+
+                let element = || format!("<{}>", String::from_utf8_lossy(&e));
+
+                match e.name().as_ref() {
+                    b"ruby" => {
+                        ruby_parse_state = Some(RubyParseState::new(paragraph.text_len()));
+                    }
+                    b"rb" => {
+                        if let Some(ref mut ruby) = ruby_parse_state {
+                            ruby.base_start = paragraph.text_len();
+                        }
+                    }
+                    b"rt" => {
+                        if let Some(ref mut ruby) = ruby_parse_state {
+                            ruby.reading = Some(Vec::new());
+                        }
+                    }
+                    b"rtc" => {
+                        if let Some(ref mut ruby) = ruby_parse_state {
+                            ruby.in_rtc = true;
+                            ruby.rtc_text.clear();
+                        }
+                    }
+                    b"img" => match parse_img_src(e.attributes(), profile) {
+                        ImgSrc::Gaiji(src) => {
+                            let mut encoded = gaiji.mapped(&resolve_src(base_dir, &src));
+                            if encoded.is_empty() {
+                                let warning = error(TextErrorKind::MissingGaiji {
+                                    src: String::from_utf8_lossy(&src).into_owned(),
+                                });
+                                error_mode.recover(warning, &mut warnings)?;
+                                encoded = &[GETA_MARK];
+                            };
+
+                            if let Some(data) =
+                                text_destination(&mut paragraph, &mut ruby_parse_state)
+                            {
+                                data.extend_from_slice(encoded);
+                            }
+                        }
+                        ImgSrc::Illustration(src) => {
+                            match image_files.index_of(&resolve_src(base_dir, &src)) {
+                                Some(image_idx) => push_image(
+                                    &mut paragraphs,
+                                    &mut paragraph,
+                                    &mut ruby_parse_state,
+                                    &mut open_inlines,
+                                    image_idx,
+                                ),
+                                None => {
+                                    let warning =
+                                        error(TextErrorKind::UnknownImage { element: element() });
+                                    error_mode.recover(warning, &mut warnings)?;
+                                    push_alt_text(&mut paragraph, e.attributes());
+                                }
+                            }
+                        }
+                        ImgSrc::None => {
+                            let warning = error(TextErrorKind::MissingSrc { element: element() });
+                            error_mode.recover(warning, &mut warnings)?;
+                            push_alt_text(&mut paragraph, e.attributes());
+                        }
+                    },
+                    b"image" => {
+                        // images within a <svg>
+                        match get_attr(e.attributes(), b"href")
+                            .and_then(|href| image_files.index_of(&resolve_src(base_dir, &href)))
+                        {
+                            Some(image_idx) => push_image(
+                                &mut paragraphs,
+                                &mut paragraph,
+                                &mut ruby_parse_state,
+                                &mut open_inlines,
+                                image_idx,
+                            ),
+                            None => {
+                                let warning =
+                                    error(TextErrorKind::UnknownImage { element: element() });
+                                error_mode.recover(warning, &mut warnings)?;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(quick_xml::events::Event::Text(e)) if non_text_depth == 0 => {
+                if let Some(data) = text_destination(&mut paragraph, &mut ruby_parse_state) {
+                    let text = text_str(&e).map_err(|e| error(TextErrorKind::Xml(e)))?;
+
+                    data.extend(unescape(text).encode_utf16());
+                }
+            }
+            Ok(quick_xml::events::Event::CData(e)) if non_text_depth == 0 => {
+                error_mode.recover(error(TextErrorKind::CData), &mut warnings)?;
+
+                // The contents of a CDATA section are plain text
+                if let Some(data) = text_destination(&mut paragraph, &mut ruby_parse_state) {
+                    let text = text_str(&e).map_err(|e| error(TextErrorKind::Xml(e)))?;
+
+                    data.extend(text.encode_utf16());
+                }
+            }
+            Ok(quick_xml::events::Event::End(e)) => {
+                open_styles.pop();
+
+                match e.local_name().as_ref() {
+                    name if is_text_block(name) => {
+                        split_inlines(&mut paragraph, &mut open_inlines);
+                        let ended = mem::replace(&mut paragraph, ParagraphParseState::None);
+                        push_paragraph(&mut paragraphs, ended);
+
+                        // Text after this element belongs to the element containing it
+                        open_blocks.pop();
+                        if let Some(&style) = open_blocks.last() {
+                            paragraph = ParagraphParseState::new(style, false);
+                        }
+                    }
+                    name if is_inline_style(name) => {
+                        if let Some(inline) = open_inlines.pop() {
+                            push_spans(&mut paragraph, &inline);
+                        }
+                    }
+                    name if is_non_text(name) => {
+                        non_text_depth = non_text_depth.saturating_sub(1);
+                    }
+                    b"rb" => {
+                        if let Some(ref mut ruby) = ruby_parse_state {
+                            let text_len = paragraph.text_len();
+                            ruby.bases.push(ruby.base_start..text_len);
+                            ruby.base_start = text_len;
+                        }
+                    }
+                    b"rt" => {
+                        if let Some(ref mut ruby) = ruby_parse_state
+                            && let Some(reading) = ruby.reading.take()
+                        {
+                            let secondary = ruby.in_rtc;
+                            let base = ruby.reading_base(paragraph.text_len());
+                            if let Err(reading) =
+                                push_ruby(&mut paragraph, base, reading, secondary)
+                            {
+                                // Leave out the reading
+                                let warning = error(TextErrorKind::RubyTooLong {
+                                    element: format!(
+                                        "<rt>{}</rt>",
+                                        String::from_utf16_lossy(&reading)
+                                    ),
+                                });
+                                error_mode.recover(warning, &mut warnings)?;
+                            }
+                        }
+                    }
+                    b"rtc" => {
+                        if let Some(ref mut ruby) = ruby_parse_state {
+                            ruby.in_rtc = false;
+
+                            // Text directly within the <rtc> applies to the whole base text
+                            let text = mem::take(&mut ruby.rtc_text);
+                            let reading = text[without_markup_whitespace(&text)].to_vec();
+                            if !reading.is_empty() {
+                                let base = ruby.start..paragraph.text_len();
+                                if let Err(reading) = push_ruby(&mut paragraph, base, reading, true)
+                                {
+                                    let warning = error(TextErrorKind::RubyTooLong {
+                                        element: format!(
+                                            "<rtc>{}</rtc>",
+                                            String::from_utf16_lossy(&reading)
+                                        ),
+                                    });
+                                    error_mode.recover(warning, &mut warnings)?;
+                                }
+                            }
+                        }
+                    }
+                    b"ruby" => {
+                        ruby_parse_state = None;
+                    }
+                    _ => {}
+                }
+            }
+            Err(e) => {
+                let position = reader.error_position();
+                return Err(TextError::at(content, position, TextErrorKind::Xml(e)));
+            }
+            Ok(quick_xml::events::Event::Eof) => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    // In case the last element isn't closed
+    push_paragraph(&mut paragraphs, paragraph);
+
+    Ok(ParsedText {
+        paragraphs,
+        anchors,
+        warnings,
+    })
+}
+
+/// linked_stylesheets returns the stylesheets that the `<link>` elements in the `<head>` of a text
+/// file point to, in order. They're found before parsing the rest of the file, since they apply
+/// to the `<html>` element before them too. Links to files which aren't stylesheets in the book
+/// are left out, and so are the links after any error, which parsing the file reports.
+fn linked_stylesheets<'a>(
+    content: &str,
+    base_dir: &str,
+    stylesheets: &'a Stylesheets,
+) -> Vec<&'a Stylesheet> {
+    let mut linked = Vec::new();
+    let mut reader = Reader::from_str(content);
+
+    loop {
+        match reader.read_event() {
+            Ok(quick_xml::events::Event::Start(e)) | Ok(quick_xml::events::Event::Empty(e)) => {
+                match e.name().as_ref() {
+                    b"body" => break,
+                    b"link"
+                        if get_attr(e.attributes(), b"rel").is_some_and(|rel| {
+                            rel.split(|&c| c == b' ')
+                                .any(|rel| rel.eq_ignore_ascii_case(b"stylesheet"))
+                        }) =>
+                    {
+                        if let Some(stylesheet) = get_attr(e.attributes(), b"href")
+                            .and_then(|href| stylesheets.get(&resolve_src(base_dir, &href)))
+                        {
+                            linked.push(stylesheet);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(quick_xml::events::Event::End(e)) if e.name().as_ref() == b"head" => break,
+            Ok(quick_xml::events::Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+
+    linked
+}
+
+/// push_paragraph adds the text of a paragraph which has ended to `paragraphs`, unless it's blank
+/// and doesn't need to be kept.
+fn push_paragraph(paragraphs: &mut Vec<Paragraph>, paragraph: ParagraphParseState) {
+    if let ParagraphParseState::Content {
+        mut paragraph,
+        keep_if_blank,
+    } = paragraph
+        && (keep_if_blank || !is_blank(&paragraph.text))
+    {
+        if !keep_if_blank {
+            trim_markup_whitespace(&mut paragraph);
+        }
+        paragraph.spans.sort_by_key(|span| span.start_offset);
+        paragraph
+            .emphasis
+            .sort_by_key(|emphasis| emphasis.start_offset);
+
+        paragraphs.push(paragraph);
+    }
+}
+
+/// push_image adds an image to `paragraphs`. When it's within a paragraph, the text before it is
+/// added first as a separate paragraph, unless it's just whitespace, and the text after it
+/// continues in a new paragraph with the same formatting.
+fn push_image(
+    paragraphs: &mut Vec<Paragraph>,
+    paragraph: &mut ParagraphParseState,
+    ruby_parse_state: &mut Option<RubyParseState>,
+    open_inlines: &mut [OpenInline],
+    image_idx: u32,
+) {
+    split_inlines(paragraph, open_inlines);
+
+    if let ParagraphParseState::Content {
+        paragraph,
+        keep_if_blank,
+    } = paragraph
+    {
+        let continued = Paragraph {
+            flags: paragraph.flags,
+            heading_level: paragraph.heading_level,
+            ..Default::default()
+        };
+        let ended = mem::replace(paragraph, continued);
+        if !is_blank(&ended.text) {
+            push_paragraph(
+                paragraphs,
+                ParagraphParseState::Content {
+                    paragraph: ended,
+                    keep_if_blank: *keep_if_blank,
+                },
+            );
+        }
+        *keep_if_blank = false;
+
+        // A reading after the image only covers the text after it
+        if let Some(ruby) = ruby_parse_state {
+            *ruby = RubyParseState::new(0);
+        }
+    }
+
+    paragraphs.push(Paragraph {
+        image_idx: Some(image_idx),
+        ..Default::default()
+    });
+}
+
+/// split_inlines adds the styles of the inline elements which contain the current position to
+/// the text of the current paragraph before it ends, so that they continue at the start of the
+/// next paragraph.
+fn split_inlines(paragraph: &mut ParagraphParseState, open_inlines: &mut [OpenInline]) {
+    for inline in open_inlines {
+        push_spans(paragraph, inline);
+        inline.start = 0;
+    }
+}
+
+/// push_ruby adds a reading for the `base` text of the current paragraph. Readings whose base is
+/// empty or no longer in the paragraph are left out. The reading is returned when it or its base
+/// is too long to encode.
+fn push_ruby(
+    paragraph: &mut ParagraphParseState,
+    base: Range<usize>,
+    reading: Vec<u16>,
+    secondary: bool,
+) -> Result<(), Vec<u16>> {
+    let ParagraphParseState::Content { paragraph, .. } = paragraph else {
+        return Ok(());
+    };
+    if base.is_empty() || base.end > paragraph.text.len() {
+        return Ok(());
+    }
+
+    let (Ok(start_offset), Ok(length), Ok(_)) = (
+        base.start.try_into(),
+        base.len().try_into(),
+        u8::try_from(reading.len() * 2),
+    ) else {
+        return Err(reading);
+    };
+
+    let ruby = if secondary {
+        &mut paragraph.secondary_ruby
+    } else {
+        &mut paragraph.ruby
+    };
+    ruby.push(Ruby {
+        start_offset,
+        length,
+        reading: reading.into_boxed_slice(),
+    });
+
+    Ok(())
+}
+
+/// push_spans adds the styles and emphasis marks of an inline element to the text of the current
+/// paragraph from where the element starts. They're left out when the element doesn't have any
+/// text, and in the rare case that the text is too far into a long paragraph for the offset to be
+/// stored.
+fn push_spans(paragraph: &mut ParagraphParseState, inline: &OpenInline) {
+    let ParagraphParseState::Content { paragraph, .. } = paragraph else {
+        return;
+    };
+
+    let (Ok(start_offset), Ok(end)) = (
+        u16::try_from(inline.start),
+        u16::try_from(paragraph.text.len()),
+    ) else {
+        return;
+    };
+    if start_offset >= end {
+        return;
+    }
+
+    let length = end - start_offset;
+    paragraph
+        .spans
+        .extend(inline.styles.iter().map(|&style| StyleSpan {
+            start_offset,
+            length,
+            style,
+        }));
+    if let Some(style) = inline.emphasis {
+        paragraph.emphasis.push(Emphasis {
+            start_offset,
+            length,
+            style,
+        });
+    }
+}
+
+/// text_destination returns where text at the current position goes: the reading of an `<rt>`, the
+/// text of an `<rtc>` or otherwise the text of the current paragraph.
+fn text_destination<'a>(
+    paragraph: &'a mut ParagraphParseState,
+    ruby_parse_state: &'a mut Option<RubyParseState>,
+) -> Option<&'a mut Vec<u16>> {
+    if let Some(ruby) = ruby_parse_state {
+        if let Some(ref mut reading) = ruby.reading {
+            return Some(reading);
+        }
+        if ruby.in_rtc {
+            return Some(&mut ruby.rtc_text);
+        }
+    }
+
+    match paragraph {
+        ParagraphParseState::Content { paragraph, .. } => Some(&mut paragraph.text),
+        ParagraphParseState::None => None,
+    }
+}
+
+/// trim_markup_whitespace removes the whitespace which lays out the markup (e.g. the line breaks
+/// between elements) from the start and end of the text of `paragraph`.
+fn trim_markup_whitespace(paragraph: &mut Paragraph) {
+    let Range { start, end } = without_markup_whitespace(&paragraph.text);
+    paragraph.text.truncate(end);
+    paragraph.text.drain(..start);
+
+    // Ruby is only lost if its base is entirely whitespace
+    for ruby in [&mut paragraph.ruby, &mut paragraph.secondary_ruby] {
+        ruby.retain_mut(|r| {
+            let base_start = usize::from(r.start_offset);
+            let base_end = base_start + usize::from(r.length);
+            if base_start < start || base_end > end {
+                return false;
+            }
+
+            r.start_offset -= start as u16;
+            true
+        });
+    }
+
+    paragraph.spans = clip_spans(&paragraph.spans, start..end);
+    paragraph.emphasis = clip_spans(&paragraph.emphasis, start..end);
+}
+
+/// Span is an annotation which applies to some of the text of a paragraph, and which can be split
+/// along with the text, unlike ruby.
+trait Span: Copy {
+    fn range(&self) -> Range<usize>;
+
+    /// with_range returns the annotation applying to `range` instead, which has to be within the
+    /// first u16::MAX code units.
+    fn with_range(self, range: Range<usize>) -> Self;
+}
+
+impl Span for StyleSpan {
+    fn range(&self) -> Range<usize> {
+        let start = usize::from(self.start_offset);
+        start..start + usize::from(self.length)
+    }
+
+    fn with_range(self, range: Range<usize>) -> Self {
+        Self {
+            start_offset: range.start as u16,
+            length: range.len() as u16,
+            ..self
+        }
+    }
+}
+
+impl Span for Emphasis {
+    fn range(&self) -> Range<usize> {
+        let start = usize::from(self.start_offset);
+        start..start + usize::from(self.length)
+    }
+
+    fn with_range(self, range: Range<usize>) -> Self {
+        Self {
+            start_offset: range.start as u16,
+            length: range.len() as u16,
+            ..self
+        }
+    }
+}
+
+/// clip_spans returns the part of each of `spans` which is within `range`, relative to the start
+/// of `range`. Spans outside of it are left out.
+fn clip_spans<T: Span>(spans: &[T], range: Range<usize>) -> Vec<T> {
+    spans
+        .iter()
+        .filter_map(|span| {
+            let span_range = span.range();
+            let start = span_range.start.max(range.start);
+            let end = span_range.end.min(range.end);
+            (start < end).then(|| span.with_range(start - range.start..end - range.start))
+        })
+        .collect()
+}
+
+/// offset_spans returns `spans` moved `offset` code units further into the text.
+fn offset_spans<T: Span>(spans: Vec<T>, offset: usize) -> impl Iterator<Item = T> {
+    spans.into_iter().map(move |span| {
+        let range = span.range();
+        span.with_range(range.start + offset..range.end + offset)
+    })
+}
+
+/// without_markup_whitespace returns the range of `text` without the whitespace which lays out the
+/// markup at its start and end. Full-width spaces are kept, since they're used for indentation.
+fn without_markup_whitespace(text: &[u16]) -> Range<usize> {
+    let is_markup_whitespace = |c: &&u16| matches!(**c, 0x20 | 0x09 | 0x0a | 0x0d);
+
+    let end = text.len() - text.iter().rev().take_while(is_markup_whitespace).count();
+    let start = text[..end].iter().take_while(is_markup_whitespace).count();
+
+    start..end
+}
+
+/// is_blank returns whether `text` is empty or only contains whitespace.
+fn is_blank(text: &[u16]) -> bool {
+    char::decode_utf16(text.iter().copied()).all(|c| c.is_ok_and(char::is_whitespace))
+}
+
+/// push_alt_text adds the alt text of an image which can't be shown to the current paragraph.
+fn push_alt_text(paragraph: &mut ParagraphParseState, attributes: Attributes<'_>) {
+    let ParagraphParseState::Content { paragraph, .. } = paragraph else {
+        return;
+    };
+    if let Some(alt) = get_attr(attributes, b"alt") {
+        paragraph
+            .text
+            .extend(unescape(&String::from_utf8_lossy(&alt)).encode_utf16());
+    }
+}
+
+/// resolve_src returns the path within the archive of the image or stylesheet that `src` points
+/// to.
+fn resolve_src(base_dir: &str, src: &[u8]) -> String {
+    resolve_href(base_dir, &String::from_utf8_lossy(src))
+}
+
+enum ImgSrc<'a> {
+    Gaiji(Cow<'a, [u8]>),
+    Illustration(Cow<'a, [u8]>),
+    None,
+}
+
+/// parse_img_src returns the source of an `<img>` element, and whether it's a 外字 according to
+/// its class names.
+fn parse_img_src<'a>(mut attributes: Attributes<'a>, profile: &Profile) -> ImgSrc<'a> {
+    let mut src = Cow::Borrowed(b"".as_slice());
+    let mut class = Cow::Borrowed(b"".as_slice());
+    for attr in attributes.with_checks(false).flatten() {
+        match attr.key.as_ref() {
+            b"src" => src = attr.value,
+            b"class" => class = attr.value,
+            _ => {}
+        }
+    }
+
+    if src.is_empty() {
+        return ImgSrc::None;
+    }
+
+    if class
+        .split(|&c| c == b' ')
+        .any(|name| profile.is_gaiji_class(name))
+    {
+        ImgSrc::Gaiji(src)
+    } else {
+        ImgSrc::Illustration(src)
+    }
+}
+
+/// is_inline_style returns whether an element can format the text within it without starting a
+/// new paragraph.
+fn is_inline_style(name: &[u8]) -> bool {
+    matches!(
+        name,
+        b"b" | b"strong" | b"i" | b"em" | b"big" | b"small" | b"span"
+    )
+}
+
+/// declared_style returns the style that an element sets itself. This is the default style of
+/// elements with its name, then the styles that publishers' class names stand for, then the
+/// rules of the stylesheets of the file, then the class names of `profile` and lastly its `style`
+/// attribute, each taking precedence over the ones before.
+fn declared_style(e: &BytesStart<'_>, stylesheets: &[&Stylesheet], profile: &Profile) -> Style {
+    let name = e.name();
+    let class = get_attr(e.attributes(), b"class");
+
+    let mut style = element_style(name.as_ref());
+    if let Some(ref class) = class {
+        style = style.with(class_style(class));
+    }
+    if !stylesheets.is_empty() {
+        style = style.with(cascade(stylesheets, name.as_ref(), class.as_deref()));
+    }
+    if let Some(ref class) = class {
+        style = style.with(profile.class_style(class));
+    }
+    if let Some(attr) = get_attr(e.attributes(), b"style") {
+        style = style.with(Style::parse(&String::from_utf8_lossy(&attr)));
+    }
+
+    style
+}
+
+/// element_style returns the style that elements with the name `name` have by default.
+fn element_style(name: &[u8]) -> Style {
+    match name {
+        b"b" | b"strong" => Style {
+            bold: Some(true),
+            ..Default::default()
+        },
+        b"i" | b"em" => Style {
+            italic: Some(true),
+            ..Default::default()
+        },
+        b"big" => Style {
+            font_size: Some(FontSize::Larger),
+            ..Default::default()
+        },
+        b"small" => Style {
+            font_size: Some(FontSize::Smaller),
+            ..Default::default()
+        },
+        _ => Style::default(),
+    }
+}
+
+/// class_style returns the style that the class names in `class` stand for by convention, which
+/// some books rely on without their stylesheets saying so.
+fn class_style(class: &[u8]) -> Style {
+    let mut style = Style::default();
+
+    for name in class.split(|&c| c == b' ') {
+        match name {
+            b"bold" => style.bold = Some(true),
+            b"tcy" | b"tate-chu-yoko" | b"tatechuyoko" => style.upright = Some(true),
+            b"boten" | b"bouten" => style.emphasis = Some(Some(EmphasisStyle::FilledSesame)),
+            _ => {}
+        }
+
+        // This may be a change in font size in terms of percent or em. For example: font-110per
+        // or font-1em30 for an increase, and font-080per or font-0em80 for a decrease.
+        let is_font_size = |prefix: &[u8]| {
+            name.starts_with(prefix)
+                && (name.ends_with(b"per") || name.len() > prefix.len() + "em".len())
+        };
+        if is_font_size(b"font-1") {
+            style.font_size = Some(FontSize::Larger);
+        } else if is_font_size(b"font-0") {
+            style.font_size = Some(FontSize::Smaller);
+        }
+
+        // Emphasis marks, e.g. em-sesame or em-dot-open
+        if let Some(shape) = name.strip_prefix(b"em-") {
+            let emphasis = match shape.strip_suffix(b"-open") {
+                Some(shape) => emphasis_shape(shape, true),
+                None => emphasis_shape(shape, false),
+            };
+            if emphasis.is_some() {
+                style.emphasis = Some(emphasis);
+            }
+        }
+    }
+
+    style
+}
+
+/// block_flags returns the flags of the paragraphs of a text block element with `style`.
+fn block_flags(style: &Style) -> u8 {
+    let mut flags = 0;
+    if style.bold == Some(true) {
+        flags |= 1 << 0;
+    }
+    if style.font_size == Some(FontSize::Larger) {
+        flags |= 1 << 1;
+    }
+    match style.text_align {
+        Some(TextAlign::Center) => flags |= 1 << 3,
+        Some(TextAlign::End) => flags |= 1 << 4,
+        _ => {}
+    }
+    match style.text_indent {
+        Some(TextIndent::Indent) => flags |= 1 << 5,
+        Some(TextIndent::Hanging) => flags |= 1 << 6,
+        _ => {}
+    }
+    if style.writing_mode == Some(WritingMode::Vertical) {
+        flags |= 1 << 7;
+    }
+
+    flags
+}
+
+/// inline_spans returns the styles and emphasis marks that an inline element with `style` gives
+/// its text, which are the ones that the element containing it with `parent` doesn't have.
+fn inline_spans(parent: &Style, style: &Style) -> (Vec<SpanStyle>, Option<EmphasisStyle>) {
+    let emphasis = style
+        .emphasis
+        .flatten()
+        .filter(|_| style.emphasis != parent.emphasis);
+    let starts = |property: Option<bool>, parent: Option<bool>| {
+        property == Some(true) && parent != Some(true)
+    };
+
+    let mut styles = Vec::new();
+    if starts(style.bold, parent.bold) {
+        styles.push(SpanStyle::Bold);
+    }
+    // Japanese books mark 傍点 with <em>, which isn't meant to be italic
+    if starts(style.italic, parent.italic) && emphasis.is_none() {
+        styles.push(SpanStyle::Italic);
+    }
+    if style.font_size != parent.font_size {
+        match style.font_size {
+            Some(FontSize::Larger) => styles.push(SpanStyle::Larger),
+            Some(FontSize::Smaller) => styles.push(SpanStyle::Smaller),
+            _ => {}
+        }
+    }
+    if starts(style.upright, parent.upright) {
+        styles.push(SpanStyle::Upright);
+    }
+
+    (styles, emphasis)
+}
+
+/// MAX_BLOCK_LEN is the most UTF-16 code units that the text of a block can have. The file format
+/// stores the length in bytes in 13 bits.
+const MAX_BLOCK_LEN: usize = (1 << 12) - 1;
+
+/// MAX_BLOCK_RUBY is the most ruby that a block can have.
+const MAX_BLOCK_RUBY: usize = u8::MAX as usize;
+
+/// merge_paragraphs combines runs of short paragraphs into blocks, and splits paragraphs which are
+/// too long for a single block. Along with the blocks, it returns the index of the block that each
+/// paragraph ended up in (the first one for paragraphs which were split).
+pub(super) fn merge_paragraphs(paragraphs: Vec<Paragraph>) -> (Vec<ContentBlock>, Vec<usize>) {
+    let mut blocks = Vec::with_capacity(128);
+    let mut paragraph_blocks = Vec::with_capacity(paragraphs.len());
+
+    let mut last_paragraph: Option<Paragraph> = None;
+    for paragraph in paragraphs {
+        if let Some(index) = paragraph.image_idx {
+            if let Some(previous) = last_paragraph.take() {
+                blocks.push(previous.into_block());
+            }
+
+            paragraph_blocks.push(blocks.len());
+            blocks.push(ContentBlock::Image { index });
+            continue;
+        }
+
+        if paragraph.text.len() > MAX_BLOCK_LEN
+            || paragraph.ruby.len() > MAX_BLOCK_RUBY
+            || paragraph.secondary_ruby.len() > MAX_BLOCK_RUBY
+        {
+            if let Some(previous) = last_paragraph.take() {
+                blocks.push(previous.into_block());
+            }
+
+            paragraph_blocks.push(blocks.len());
+
+            let mut pieces = split_paragraph(paragraph);
+            // Like any other paragraph without formatting, the last piece can be merged with the
+            // paragraphs after it
+            if pieces.last().is_some_and(|last| !last.is_formatted()) {
+                last_paragraph = pieces.pop();
+            }
+
+            for piece in pieces {
+                blocks.push(piece.into_block());
+            }
+
+            continue;
+        }
+
+        // If two adjacent paragraphs have the same formatting, merging them is possible. But these
+        // paragraphs are so rare that it's simpler to leave them unmerged. Headings are always
+        // blocks of their own, so that readers can find them.
+        if paragraph.is_formatted() {
+            if let Some(previous) = last_paragraph.take() {
+                blocks.push(previous.into_block());
+            }
+
+            paragraph_blocks.push(blocks.len());
+            blocks.push(paragraph.into_block());
+            continue;
+        }
+
+        // Start a new block so that the table of contents can point to the start of the section.
+        if let Some(previous) = last_paragraph.take_if(|_| paragraph.starts_section) {
+            blocks.push(previous.into_block());
+        }
+
+        let Some(mut previous) = last_paragraph else {
+            paragraph_blocks.push(blocks.len());
+            last_paragraph = Some(paragraph);
+            continue;
+        };
+
+        // Check if merging would result in a block that's too long.
+        // The file format can support longer runs of text, but it's preferable to have text that
+        // isn't too long so that all the text in a block can be measured and laid out at once.
+        // The layout applies to the whole block, so it has to be the same for both paragraphs.
+        if previous.text.len() + paragraph.text.len() > 127
+            || previous.ruby.len() + paragraph.ruby.len() > 127
+            || previous.secondary_ruby.len() + paragraph.secondary_ruby.len() > 127
+            || previous.layout_flags() != paragraph.layout_flags()
+        {
+            blocks.push(previous.into_block());
+
+            paragraph_blocks.push(blocks.len());
+            last_paragraph = Some(paragraph);
+            continue;
+        }
+
+        paragraph_blocks.push(blocks.len());
+        previous.text.extend("\n".encode_utf16());
+
+        let new_start_offset: u16 = previous.text.len().try_into().unwrap();
+        previous.text.extend(paragraph.text);
+
+        let rebase = |mut r: Ruby| {
+            r.start_offset += new_start_offset;
+            r
+        };
+        previous.ruby.extend(paragraph.ruby.into_iter().map(rebase));
+        previous
+            .secondary_ruby
+            .extend(paragraph.secondary_ruby.into_iter().map(rebase));
+        let offset = usize::from(new_start_offset);
+        previous.spans.extend(offset_spans(paragraph.spans, offset));
+        previous
+            .emphasis
+            .extend(offset_spans(paragraph.emphasis, offset));
+
+        last_paragraph = Some(previous);
+    }
+
+    if let Some(paragraph) = last_paragraph {
+        blocks.push(paragraph.into_block());
+    }
+
+    (blocks, paragraph_blocks)
+}
+
+/// split_paragraph splits a paragraph which is too long for a single block into pieces which each
+/// fit in one. Pieces end after the end of a sentence or a line break where possible, and never in
+/// the middle of the text that a ruby applies to. The pieces after the first are marked as
+/// continuing the one before them.
+fn split_paragraph(paragraph: Paragraph) -> Vec<Paragraph> {
+    let Paragraph {
+        text,
+        ruby,
+        secondary_ruby,
+        spans,
+        emphasis,
+        flags,
+        heading_level,
+        starts_section,
+        ..
+    } = paragraph;
+
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut ruby_start = 0;
+    let mut secondary_ruby_start = 0;
+    loop {
+        let mut limit = text.len().min(start + MAX_BLOCK_LEN);
+        if let Some(r) = ruby.get(ruby_start + MAX_BLOCK_RUBY) {
+            limit = limit.min(usize::from(r.start_offset));
+        }
+        if let Some(r) = secondary_ruby.get(secondary_ruby_start + MAX_BLOCK_RUBY) {
+            limit = limit.min(usize::from(r.start_offset));
+        }
+
+        let end = if limit == text.len() {
+            limit
+        } else {
+            let splits_base = |r: &Ruby, end: usize| {
+                let base_start = usize::from(r.start_offset);
+                base_start < end && end < base_start + usize::from(r.length)
+            };
+            let can_end_at = |end: usize| {
+                !is_high_surrogate(text[end - 1])
+                    && !ruby[ruby_start..].iter().any(|r| splits_base(r, end))
+                    && !secondary_ruby[secondary_ruby_start..]
+                        .iter()
+                        .any(|r| splits_base(r, end))
+            };
+
+            let candidates = || (start + 1..=limit).rev().filter(|&end| can_end_at(end));
+            candidates()
+                .find(|&end| is_sentence_end(&text, end))
+                .or_else(|| candidates().next())
+                // Only possible when there are too many ruby at the start of the piece, which
+                // the writer will report
+                .unwrap_or(start + MAX_BLOCK_LEN.min(text.len() - start))
+        };
+
+        let in_piece = |r: &&Ruby| usize::from(r.start_offset) < end || end == text.len();
+        let num_ruby = ruby[ruby_start..].iter().take_while(in_piece).count();
+        let num_secondary_ruby = secondary_ruby[secondary_ruby_start..]
+            .iter()
+            .take_while(in_piece)
+            .count();
+
+        // A paragraph with ruby is shorter than u16::MAX, since ruby outside of that are left out
+        let rebase = |ruby: &[Ruby]| {
+            ruby.iter()
+                .map(|r| Ruby {
+                    start_offset: r.start_offset - start as u16,
+                    length: r.length,
+                    reading: r.reading.clone(),
+                })
+                .collect()
+        };
+        pieces.push(Paragraph {
+            text: text[start..end].to_vec(),
+            image_idx: None,
+            ruby: rebase(&ruby[ruby_start..ruby_start + num_ruby]),
+            secondary_ruby: rebase(
+                &secondary_ruby[secondary_ruby_start..secondary_ruby_start + num_secondary_ruby],
+            ),
+            // Spans can be split, so each piece has the part of them which is within it
+            spans: clip_spans(&spans, start..end),
+            emphasis: clip_spans(&emphasis, start..end),
+            flags: if start == 0 { flags } else { flags | 1 << 2 },
+            heading_level,
+            starts_section: starts_section && start == 0,
+        });
+
+        if end == text.len() {
+            return pieces;
+        }
+
+        start = end;
+        ruby_start += num_ruby;
+        secondary_ruby_start += num_secondary_ruby;
+    }
+}
+
+fn is_high_surrogate(unit: u16) -> bool {
+    (0xd800..0xdc00).contains(&unit)
+}
+
+/// is_sentence_end returns whether `end` is directly after the end of a sentence (。！？) or a line
+/// break in `text`, including any closing brackets after the sentence.
+fn is_sentence_end(text: &[u16], end: usize) -> bool {
+    const ENDS: [u16; 4] = [0x3002, 0xff01, 0xff1f, b'\n' as u16];
+    const CLOSING: [u16; 4] = [0x300d, 0x300f, 0xff09, 0x3011];
+
+    let continues = text
+        .get(end)
+        .is_some_and(|c| ENDS.contains(c) || CLOSING.contains(c));
+    if continues {
+        return false;
+    }
+
+    text[..end]
+        .iter()
+        .rev()
+        .find(|c| !CLOSING.contains(c))
+        .is_some_and(|c| ENDS.contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::style::parse_stylesheet;
+    use std::collections::HashMap;
+
+    /// parse parses `content` as a text file which doesn't refer to anything else in the
+    /// book, stopping at the first problem.
+    fn parse(content: &str) -> Result<ParsedText, TextError> {
+        parse_text_file(
+            content,
+            "",
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            ErrorMode::Strict,
+        )
+    }
+
+    /// parse_leniently is like [`parse`], but works around problems.
+    fn parse_leniently(content: &str) -> Result<ParsedText, TextError> {
+        parse_text_file(
+            content,
+            "",
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            ErrorMode::Lenient,
+        )
+    }
+
+    #[test]
+    fn parse_paragraph() {
+        let content = String::from("<p>test</p>");
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        assert_eq!(paragraphs.len(), 1);
+
+        let expected = Paragraph {
+            text: "test".encode_utf16().collect(),
+            ..Default::default()
+        };
+        assert_eq!(paragraphs[0], expected);
+    }
+
+    #[test]
+    fn parse_paragraph_ruby() {
+        let content = String::from("<p><ruby>開発<rt>かいはつ</rt></ruby></p>");
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        assert_eq!(paragraphs.len(), 1);
+
+        let expected = Paragraph {
+            text: "開発".encode_utf16().collect(),
+            ruby: Vec::from([Ruby {
+                start_offset: 0,
+                length: 2,
+                reading: "かいはつ".encode_utf16().collect(),
+            }]),
+            ..Default::default()
+        };
+        assert_eq!(paragraphs[0], expected);
+    }
+
+    #[test]
+    fn parse_references() {
+        let content = String::from(
+            "<p>&lt;本&gt;&#x2015;<ruby>&amp;<rt>&#12354;&nbsp;&quot;</rt></ruby><img src=\"a.jpg\" alt=\"&#25407;&amp;\"/></p>",
+        );
+
+        let paragraphs = parse_leniently(&content).unwrap().paragraphs;
+
+        let expected = Paragraph {
+            text: "<本>―&挿&".encode_utf16().collect(),
+            ruby: Vec::from([Ruby {
+                start_offset: 4,
+                length: 1,
+                reading: "あ\u{a0}\"".encode_utf16().collect(),
+            }]),
+            ..Default::default()
+        };
+        assert_eq!(paragraphs, [expected]);
+    }
+
+    #[test]
+    fn parse_paragraph_ruby_rb() {
+        let content = String::from("<p><ruby><rb>開発</rb><rt>かいはつ</rt></ruby></p>");
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        assert_eq!(paragraphs.len(), 1);
+
+        let expected = Paragraph {
+            text: "開発".encode_utf16().collect(),
+            ruby: Vec::from([Ruby {
+                start_offset: 0,
+                length: 2,
+                reading: "かいはつ".encode_utf16().collect(),
+            }]),
+            ..Default::default()
+        };
+        assert_eq!(paragraphs[0], expected);
+    }
+
+    #[test]
+    fn parse_paragraph_ruby_multiple_rt() {
+        let content = String::from("<p><ruby>開<rt>かい</rt>発<rt>はつ</rt></ruby></p>");
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        assert_eq!(paragraphs.len(), 1);
+
+        let expected = Paragraph {
+            text: "開発".encode_utf16().collect(),
+            ruby: Vec::from([
+                Ruby {
+                    start_offset: 0,
+                    length: 1,
+                    reading: "かい".encode_utf16().collect(),
+                },
+                Ruby {
+                    start_offset: 1,
+                    length: 1,
+                    reading: "はつ".encode_utf16().collect(),
+                },
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(paragraphs[0], expected);
+    }
+
+    #[test]
+    fn parse_ruby_reading_too_long() {
+        // The reading takes 256 bytes, but its length is stored in a u8
+        let content = format!("<p><ruby>字<rt>{}</rt></ruby></p>", "じ".repeat(128));
+
+        let error = parse(&content).unwrap_err();
+
+        assert!(
+            matches!(error.kind, TextErrorKind::RubyTooLong { .. }),
+            "{error:?}"
+        );
+
+        let text = parse_leniently(&content).unwrap();
+
+        assert_eq!(
+            text.paragraphs,
+            [Paragraph {
+                text: "字".encode_utf16().collect(),
+                ..Default::default()
+            }]
+        );
+        assert_eq!(text.warnings.len(), 1);
+    }
+
+    /// ruby returns a ruby with `reading` for `length` characters at `start_offset`.
+    fn ruby(start_offset: u16, length: u8, reading: &str) -> Ruby {
+        Ruby {
+            start_offset,
+            length,
+            reading: reading.encode_utf16().collect(),
+        }
+    }
+
+    #[test]
+    fn parse_ruby_rp_and_nested_markup() {
+        let content = String::from(
+            "<p><ruby>漢字<rp>（</rp><rt><span class=\"k\">かん</span>じ</rt><rp>）</rp></ruby>を</p>",
+        );
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        let expected = Paragraph {
+            text: "漢字を".encode_utf16().collect(),
+            ruby: Vec::from([ruby(0, 2, "かんじ")]),
+            ..Default::default()
+        };
+        assert_eq!(paragraphs, [expected]);
+    }
+
+    #[test]
+    fn parse_ruby_rb_and_rtc() {
+        let content = String::from(
+            "<p><ruby><rb>東</rb><rb>京</rb><rt>とう</rt><rt>きょう</rt>\
+             <rtc><rt>ひがし</rt><rt>みやこ</rt></rtc></ruby>と\
+             <ruby>大阪<rt>おおさか</rt><rtc> Osaka </rtc></ruby></p>",
+        );
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        let expected = Paragraph {
+            text: "東京と大阪".encode_utf16().collect(),
+            ruby: Vec::from([
+                ruby(0, 1, "とう"),
+                ruby(1, 1, "きょう"),
+                ruby(3, 2, "おおさか"),
+            ]),
+            secondary_ruby: Vec::from([
+                ruby(0, 1, "ひがし"),
+                ruby(1, 1, "みやこ"),
+                ruby(3, 2, "Osaka"),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(paragraphs, [expected]);
+    }
+
+    #[test]
+    fn parse_ruby_gaiji() {
+        let content = String::from(
+            "<p><ruby>山<img class=\"gaiji\" src=\"a.png\"/><rt>やま<img class=\"gaiji\" src=\"b.png\"/></rt></ruby></p>",
+        );
+        let gaiji = Gaiji {
+            replacements: HashMap::from([
+                ("a.png".to_string(), "𠮷".encode_utf16().collect()),
+                ("b.png".to_string(), "ゟ".encode_utf16().collect()),
+            ]),
+        };
+
+        let paragraphs = parse_text_file(
+            &content,
+            "",
+            &Default::default(),
+            &gaiji,
+            &Default::default(),
+            &Default::default(),
+            ErrorMode::Strict,
+        )
+        .unwrap()
+        .paragraphs;
+
+        let expected = Paragraph {
+            text: "山𠮷".encode_utf16().collect(),
+            ruby: Vec::from([ruby(0, 3, "やまゟ")]),
+            ..Default::default()
+        };
+        assert_eq!(paragraphs, [expected]);
+    }
+
+    #[test]
+    fn parse_image_by_path() {
+        let content = String::from(
+            "<p><img src=\"../Images/gaiji/p001.jpg\"/></p><p><img src=\"./../Images/p%30%301.jpg#x\"/></p>",
+        );
+        let image_files = ImageFiles {
+            indices: HashMap::from([
+                ("OEBPS/Images/p001.jpg".to_string(), 0),
+                ("OEBPS/Images/gaiji/p001.jpg".to_string(), 1),
+            ]),
+            ..Default::default()
+        };
+
+        let paragraphs = parse_text_file(
+            &content,
+            "OEBPS/Text",
+            &image_files,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            ErrorMode::Strict,
+        )
+        .unwrap()
+        .paragraphs;
+
+        let image_indices = paragraphs.iter().map(|p| p.image_idx).collect::<Vec<_>>();
+        assert_eq!(image_indices, [Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn parse_mixed_text_and_images() {
+        let content = String::from(
+            "<p class=\"bold\">前<ruby>挿<rt>さ</rt></ruby><img src=\"a.jpg\"/>間<img src=\"b.jpg\"/><img src=\"a.jpg\"/>後</p>\n<p>\n<img src=\"b.jpg\"/>\n</p><p></p>",
+        );
+        let image_files = ImageFiles {
+            indices: HashMap::from([("a.jpg".to_string(), 0), ("b.jpg".to_string(), 1)]),
+            ..Default::default()
+        };
+
+        let paragraphs = parse_text_file(
+            &content,
+            "",
+            &image_files,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            ErrorMode::Strict,
+        )
+        .unwrap()
+        .paragraphs;
+
+        let text = |s: &str, flags| Paragraph {
+            text: s.encode_utf16().collect(),
+            flags,
+            ..Default::default()
+        };
+        let image = |index| Paragraph {
+            image_idx: Some(index),
+            ..Default::default()
+        };
+        assert_eq!(
+            paragraphs,
+            [
+                Paragraph {
+                    ruby: vec![Ruby {
+                        start_offset: 1,
+                        length: 1,
+                        reading: "さ".encode_utf16().collect(),
+                    }],
+                    ..text("前挿", 1)
+                },
+                image(0),
+                text("間", 1),
+                image(1),
+                image(0),
+                text("後", 1),
+                image(1),
+                text("", 0),
+            ]
+        );
+    }
+
+    /// span returns a span with `style` for `length` code units at `start_offset`.
+    fn span(start_offset: u16, length: u16, style: SpanStyle) -> StyleSpan {
+        StyleSpan {
+            start_offset,
+            length,
+            style,
+        }
+    }
+
+    #[test]
+    fn parse_inline_styles() {
+        let content = String::from(
+            "<p>普通<b>太字<em>強調</em></b><span class=\"font-080per\">小</span><span class=\"x\">無</span></p>\n\
+             <div>\n<strong> 前<img src=\"a.jpg\"/>後 </strong>\n</div>",
+        );
+        let image_files = ImageFiles {
+            indices: HashMap::from([("a.jpg".to_string(), 0)]),
+            ..Default::default()
+        };
+
+        let paragraphs = parse_text_file(
+            &content,
+            "",
+            &image_files,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            ErrorMode::Strict,
+        )
+        .unwrap()
+        .paragraphs;
+
+        let bold = |s: &str| Paragraph {
+            text: s.encode_utf16().collect(),
+            spans: vec![span(0, 1, SpanStyle::Bold)],
+            ..Default::default()
+        };
+        assert_eq!(
+            paragraphs,
+            [
+                Paragraph {
+                    text: "普通太字強調小無".encode_utf16().collect(),
+                    spans: vec![
+                        span(2, 4, SpanStyle::Bold),
+                        span(4, 2, SpanStyle::Italic),
+                        span(6, 1, SpanStyle::Smaller),
+                    ],
+                    ..Default::default()
+                },
+                // The whitespace around the text is left out of the spans too
+                bold("前"),
+                Paragraph {
+                    image_idx: Some(0),
+                    ..Default::default()
+                },
+                bold("後"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_tate_chu_yoko() {
+        let content = String::from(
+            "<p>平成<span class=\"tcy\">12</span>年<span style=\"-epub-text-combine: horizontal\">!?</span>\
+             <span class=\"tcy bold\">3</span><span style=\"text-combine-upright: all\">45</span>\
+             <span style=\"text-combine-upright: none\">67</span>\
+             <span class=\"tcy\"><span class=\"tcy\">89</span></span></p>",
+        );
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        assert_eq!(
+            paragraphs,
+            [Paragraph {
+                text: "平成12年!?3456789".encode_utf16().collect(),
+                spans: vec![
+                    span(2, 2, SpanStyle::Upright),
+                    span(5, 2, SpanStyle::Upright),
+                    span(7, 1, SpanStyle::Bold),
+                    span(7, 1, SpanStyle::Upright),
+                    span(8, 2, SpanStyle::Upright),
+                    // Upright text within upright text is a single span
+                    span(12, 2, SpanStyle::Upright),
+                ],
+                ..Default::default()
+            }]
+        );
+    }
+
+    fn emphasis(start_offset: u16, length: u16, style: EmphasisStyle) -> Emphasis {
+        Emphasis {
+            start_offset,
+            length,
+            style,
+        }
+    }
+
+    #[test]
+    fn parse_emphasis() {
+        let content = String::from(
+            "<p><em class=\"em-sesame\">胡麻</em><span class=\"x em-dot-open\">点</span>\
+             <span class=\"boten\"><b>太</b></span>\
+             <span style=\"color: red; -webkit-text-emphasis-style: open circle\">丸</span>\
+             <span style=\"text-emphasis: filled red\">既</span>\
+             <span style=\"text-emphasis: none\">無</span><em>斜</em></p>",
+        );
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        assert_eq!(
+            paragraphs,
+            [Paragraph {
+                text: "胡麻点太丸既無斜".encode_utf16().collect(),
+                // Emphasis marks replace the italics of <em>
+                spans: vec![span(3, 1, SpanStyle::Bold), span(7, 1, SpanStyle::Italic)],
+                emphasis: vec![
+                    emphasis(0, 2, EmphasisStyle::FilledSesame),
+                    emphasis(2, 1, EmphasisStyle::OpenDot),
+                    emphasis(3, 1, EmphasisStyle::FilledSesame),
+                    emphasis(4, 1, EmphasisStyle::OpenCircle),
+                    emphasis(5, 1, EmphasisStyle::FilledSesame),
+                ],
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_linked_stylesheets() {
+        let content = String::from(
+            "<html class=\"vrtl\"><head><link rel=\"stylesheet\" href=\"../style/book.css\"/></head>\
+             <body><p>一</p><p class=\"center\">二</p>\
+             <p>三<span class=\"gothic\">四</span><b class=\"plain\">五</b></p>\
+             <p style=\"text-indent: 0\">六</p><h1 class=\"center\">七</h1>\
+             <div class=\"bold\">八</div></body></html>",
+        );
+        let stylesheets = Stylesheets {
+            by_path: HashMap::from([(
+                "OEBPS/style/book.css".to_string(),
+                parse_stylesheet(
+                    ".vrtl { writing-mode: vertical-rl; }\n\
+                     p { text-indent: 1em; }\n\
+                     .center { text-align: center; text-indent: 0; }\n\
+                     .gothic { font-weight: bold; }\n\
+                     .plain, .bold { font-weight: normal; }",
+                ),
+            )]),
+        };
+
+        let paragraphs = parse_text_file(
+            &content,
+            "OEBPS/text",
+            &Default::default(),
+            &Default::default(),
+            &stylesheets,
+            &Default::default(),
+            ErrorMode::Strict,
+        )
+        .unwrap()
+        .paragraphs;
+
+        let text = |s: &str, flags| Paragraph {
+            text: s.encode_utf16().collect(),
+            flags,
+            ..Default::default()
+        };
+        assert_eq!(
+            paragraphs,
+            [
+                text("一", 1 << 7 | 1 << 5),
+                text("二", 1 << 7 | 1 << 3),
+                Paragraph {
+                    spans: vec![span(1, 1, SpanStyle::Bold)],
+                    ..text("三四五", 1 << 7 | 1 << 5)
+                },
+                text("六", 1 << 7),
+                Paragraph {
+                    heading_level: 1,
+                    ..text("七", 1 << 7 | 1 << 3)
+                },
+                // The stylesheet takes precedence over the usual meaning of the class
+                text("八", 1 << 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_profile_classes() {
+        let content = String::from(
+            "<html class=\"vrtl\"><head><link rel=\"stylesheet\" href=\"style.css\"/></head>\
+             <body><p class=\"k-center k-bold\">一<span class=\"gfont\">二</span></p>\
+             <p>三<img class=\"gaiji-line\" src=\"g.png\"/></p></body></html>",
+        );
+        let stylesheets = Stylesheets {
+            by_path: HashMap::from([(
+                "style.css".to_string(),
+                parse_stylesheet(".k-center { text-align: end; }"),
+            )]),
+        };
+        let gaiji = Gaiji {
+            replacements: HashMap::from([("g.png".to_string(), "四".encode_utf16().collect())]),
+        };
+        let mut profile = Profile::builtin("ebpaj").unwrap();
+        profile.extend(
+            Profile::parse(
+                "[classes]\n\
+                 k-center = \"text-align: center\"\n\
+                 k-bold = \"font-weight: bold\"\n\
+                 gfont = \"font-size: 120%\"\n",
+            )
+            .unwrap(),
+        );
+
+        let paragraphs = parse_text_file(
+            &content,
+            "",
+            &Default::default(),
+            &gaiji,
+            &stylesheets,
+            &profile,
+            ErrorMode::Strict,
+        )
+        .unwrap()
+        .paragraphs;
+
+        assert_eq!(
+            paragraphs,
+            [
+                Paragraph {
+                    text: "一二".encode_utf16().collect(),
+                    spans: vec![span(1, 1, SpanStyle::Larger)],
+                    // The profile takes precedence over the stylesheet
+                    flags: 1 << 7 | 1 << 3 | 1,
+                    ..Default::default()
+                },
+                Paragraph {
+                    text: "三四".encode_utf16().collect(),
+                    flags: 1 << 7,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_gaiji_by_path() {
+        let content = String::from(
+            "<p><img class=\"gaiji\" src=\"../gaiji/a.png\"/><img class=\"gaiji\" src=\"b.png\"/></p>",
+        );
+        let gaiji = Gaiji {
+            replacements: HashMap::from([
+                (
+                    "OEBPS/gaiji/a.png".to_string(),
+                    "一".encode_utf16().collect(),
+                ),
+                ("a.png".to_string(), "二".encode_utf16().collect()),
+                ("b.png".to_string(), "三".encode_utf16().collect()),
+            ]),
+        };
+
+        let paragraphs = parse_text_file(
+            &content,
+            "OEBPS/Text",
+            &Default::default(),
+            &gaiji,
+            &Default::default(),
+            &Default::default(),
+            ErrorMode::Strict,
+        )
+        .unwrap()
+        .paragraphs;
+
+        assert_eq!(
+            paragraphs[0].text,
+            "一三".encode_utf16().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parse_unknown_image() {
+        let content =
+            String::from("<body>\n<p>a</p>\n<p><img src=\"../image/a.jpg\"/></p>\n</body>");
+
+        let error = parse(&content).unwrap_err();
+
+        assert_eq!(error.line, 3);
+        assert!(
+            matches!(error.kind, TextErrorKind::UnknownImage { ref element } if element == "<img src=\"../image/a.jpg\">"),
+            "{error:?}",
+        );
+    }
+
+    #[test]
+    fn parse_missing_gaiji() {
+        let content = String::from("<p>a<img class=\"gaiji\" src=\"../image/g.png\"/></p>");
+
+        let error = parse(&content).unwrap_err();
+
+        assert!(
+            matches!(error.kind, TextErrorKind::MissingGaiji { ref src } if src == "../image/g.png"),
+            "{error:?}",
+        );
+    }
+
+    #[test]
+    fn parse_cdata() {
+        let content = String::from("<p><![CDATA[a]]></p>");
+
+        let error = parse(&content).unwrap_err();
+
+        assert!(matches!(error.kind, TextErrorKind::CData), "{error:?}");
+    }
+
+    #[test]
+    fn parse_lenient() {
+        let content = String::from(
+            "<body>\n<p>a<img src=\"a.jpg\" alt=\"挿絵\"/></p>\n<p>b<img class=\"gaiji\" src=\"g.png\"/></p>\n<p><![CDATA[c]]></p>\n</body>",
+        );
+
+        let text = parse_leniently(&content).unwrap();
+
+        let paragraphs = text
+            .paragraphs
+            .iter()
+            .map(|p| String::from_utf16(&p.text).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paragraphs, vec!["a挿絵", "b〓", "c"]);
+
+        let warnings = text
+            .warnings
+            .iter()
+            .map(|w| (w.line, w.kind.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            vec![
+                (
+                    2,
+                    "doesn't point to a valid image: <img src=\"a.jpg\" alt=\"挿絵\">".to_string()
+                ),
+                (3, "failed to find mapping for gaiji g.png".to_string()),
+                (4, "unhandled CDATA section".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn merge_single() {
+        let paragraph = Paragraph {
+            text: "a".encode_utf16().collect(),
+            ..Default::default()
+        };
+
+        let (mut result, _) = merge_paragraphs(vec![paragraph]);
+
+        assert_eq!(result.len(), 1);
+
+        let ContentBlock::Text { text, .. } = result.pop().unwrap() else {
+            panic!("image");
+        };
+
+        assert_eq!(text, "a".encode_utf16().collect());
+    }
+
+    #[test]
+    fn merge_two() {
+        let paragraph_a = Paragraph {
+            text: "a".encode_utf16().collect(),
+            ..Default::default()
+        };
+        let paragraph_b = Paragraph {
+            text: "b".encode_utf16().collect(),
+            ..Default::default()
+        };
+
+        let (mut result, _) = merge_paragraphs(vec![paragraph_a, paragraph_b]);
+
+        assert_eq!(result.len(), 1);
+
+        let ContentBlock::Text { text, .. } = result.pop().unwrap() else {
+            panic!("image");
+        };
+
+        assert_eq!(text, "a\nb".encode_utf16().collect());
+    }
+
+    #[test]
+    fn merge_secondary_ruby() {
+        let paragraph_a = Paragraph {
+            text: "東京".encode_utf16().collect(),
+            secondary_ruby: Vec::from([ruby(0, 2, "Tokyo")]),
+            ..Default::default()
+        };
+        let paragraph_b = Paragraph {
+            text: "大阪".encode_utf16().collect(),
+            ruby: Vec::from([ruby(0, 2, "おおさか")]),
+            secondary_ruby: Vec::from([ruby(0, 2, "Osaka")]),
+            ..Default::default()
+        };
+
+        let (result, _) = merge_paragraphs(vec![paragraph_a, paragraph_b]);
+
+        let [
+            ContentBlock::Text {
+                ruby: primary,
+                secondary_ruby,
+                ..
+            },
+        ] = &result[..]
+        else {
+            panic!("{result:?}");
+        };
+        assert_eq!(**primary, [ruby(3, 2, "おおさか")]);
+        assert_eq!(**secondary_ruby, [ruby(0, 2, "Tokyo"), ruby(3, 2, "Osaka")]);
+    }
+
+    #[test]
+    fn merge_starts_section() {
+        let paragraph_a = Paragraph {
+            text: "a".encode_utf16().collect(),
+            ..Default::default()
+        };
+        let paragraph_b = Paragraph {
+            text: "b".encode_utf16().collect(),
+            ..Default::default()
+        };
+        let paragraph_c = Paragraph {
+            text: "c".encode_utf16().collect(),
+            starts_section: true,
+            ..Default::default()
+        };
+        let paragraph_d = Paragraph {
+            text: "d".encode_utf16().collect(),
+            ..Default::default()
+        };
+
+        let (result, paragraph_blocks) =
+            merge_paragraphs(vec![paragraph_a, paragraph_b, paragraph_c, paragraph_d]);
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(paragraph_blocks, [0, 0, 1, 1]);
+
+        let ContentBlock::Text { text, .. } = &result[1] else {
+            panic!("image");
+        };
+
+        assert_eq!(*text, "c\nd".encode_utf16().collect());
+    }
+
+    #[test]
+    fn merge_splits_long_paragraph() {
+        let sentence = "あいう。".encode_utf16().collect::<Vec<_>>();
+        let text = sentence.repeat(3000);
+        // A ruby over the last sentence before the first split
+        let ruby_start = (MAX_BLOCK_LEN / sentence.len()) * sentence.len() - 4;
+        let paragraph = Paragraph {
+            text: text.clone(),
+            ruby: vec![Ruby {
+                start_offset: ruby_start as u16,
+                length: 3,
+                reading: "かな".encode_utf16().collect(),
+            }],
+            starts_section: true,
+            ..Default::default()
+        };
+
+        let (result, paragraph_blocks) = merge_paragraphs(vec![paragraph]);
+
+        assert_eq!(paragraph_blocks, [0]);
+        assert!(result.len() > 1);
+
+        let mut joined = Vec::new();
+        for (i, block) in result.iter().enumerate() {
+            let ContentBlock::Text {
+                text, ruby, flags, ..
+            } = block
+            else {
+                panic!("image");
+            };
+
+            assert!(text.len() <= MAX_BLOCK_LEN);
+            assert_eq!(*text.last().unwrap(), 0x3002);
+            assert_eq!(*flags, if i == 0 { 0 } else { 1 << 2 });
+            if i == 0 {
+                assert_eq!(ruby[0].start_offset as usize, ruby_start);
+            } else {
+                assert!(ruby.is_empty());
+            }
+
+            joined.extend_from_slice(text);
+        }
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn split_spans() {
+        let paragraph = Paragraph {
+            text: vec![u16::from(b'a'); MAX_BLOCK_LEN + 100],
+            spans: vec![
+                span(0, 1, SpanStyle::Italic),
+                span(MAX_BLOCK_LEN as u16 - 10, 20, SpanStyle::Bold),
+            ],
+            emphasis: vec![emphasis(
+                MAX_BLOCK_LEN as u16 - 5,
+                10,
+                EmphasisStyle::FilledDot,
+            )],
+            ..Default::default()
+        };
+
+        let (result, _) = merge_paragraphs(vec![paragraph]);
+
+        let spans = result
+            .iter()
+            .map(|block| match block {
+                ContentBlock::Text {
+                    spans, emphasis, ..
+                } => (spans.to_vec(), emphasis.to_vec()),
+                ContentBlock::Image { .. } => panic!("image"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                (
+                    vec![
+                        span(0, 1, SpanStyle::Italic),
+                        span(MAX_BLOCK_LEN as u16 - 10, 10, SpanStyle::Bold),
+                    ],
+                    vec![emphasis(
+                        MAX_BLOCK_LEN as u16 - 5,
+                        5,
+                        EmphasisStyle::FilledDot
+                    )],
+                ),
+                (
+                    vec![span(0, 10, SpanStyle::Bold)],
+                    vec![emphasis(0, 5, EmphasisStyle::FilledDot)],
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_does_not_break_ruby() {
+        // Sentence ends are inside of the ruby base, so the split happens before it
+        let mut text = "あ"
+            .encode_utf16()
+            .collect::<Vec<_>>()
+            .repeat(MAX_BLOCK_LEN - 2);
+        text.extend("い。う。え".encode_utf16());
+        let paragraph = Paragraph {
+            text,
+            ruby: vec![Ruby {
+                start_offset: (MAX_BLOCK_LEN - 2) as u16,
+                length: 5,
+                reading: "かな".encode_utf16().collect(),
+            }],
+            ..Default::default()
+        };
+
+        let pieces = split_paragraph(paragraph);
+
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0].text.len(), MAX_BLOCK_LEN - 2);
+        assert!(pieces[0].ruby.is_empty());
+        assert_eq!(
+            pieces[1].ruby,
+            [Ruby {
+                start_offset: 0,
+                length: 5,
+                reading: "かな".encode_utf16().collect(),
+            }],
+        );
+        assert_eq!(pieces[1].flags, 1 << 2);
+    }
+
+    #[test]
+    fn split_too_many_ruby() {
+        let paragraph = Paragraph {
+            text: "字".encode_utf16().collect::<Vec<_>>().repeat(300),
+            ruby: (0..300)
+                .map(|i| Ruby {
+                    start_offset: i,
+                    length: 1,
+                    reading: "じ".encode_utf16().collect(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let pieces = split_paragraph(paragraph);
+
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0].ruby.len(), MAX_BLOCK_RUBY);
+        assert_eq!(pieces[1].ruby.len(), 300 - MAX_BLOCK_RUBY);
+        assert_eq!(pieces[1].ruby[0].start_offset, 0);
+    }
+
+    #[test]
+    fn parse_anchors() {
+        let content = String::from(
+            r#"<body><h1 id="c1">1</h1><p>a</p><p id="p2">b</p><div id="end"></div></body>"#,
+        );
+
+        let text = parse(&content).unwrap();
+
+        assert_eq!(
+            text.anchors,
+            [
+                Anchor {
+                    id: "c1".into(),
+                    paragraph_idx: 0,
+                },
+                Anchor {
+                    id: "p2".into(),
+                    paragraph_idx: 2,
+                },
+                Anchor {
+                    id: "end".into(),
+                    paragraph_idx: 3,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_text_outside_paragraphs() {
+        let content = String::from(
+            "<html><head><title>題</title><style>p {}</style></head><body>\n\
+             <h2 class=\"bold\">第一章</h2>\n\
+             <div>\n<p>a</p>\n\
+             b\n\
+             <ul><li>c</li><li><p>d</p></li></ul>\n\
+             </div>\n\
+             <blockquote>e</blockquote>\n\
+             <table><tr><td>f</td><th>g</th></tr></table>\n\
+             <svg><title>図</title></svg>\n\
+             h\n\
+             </body></html>",
+        );
+
+        let paragraphs = parse(&content).unwrap().paragraphs;
+
+        let text = |s: &str| Paragraph {
+            text: s.encode_utf16().collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            paragraphs,
+            [
+                Paragraph {
+                    flags: 1,
+                    heading_level: 2,
+                    ..text("第一章")
+                },
+                text("a"),
+                text("b"),
+                text("c"),
+                text("d"),
+                text("e"),
+                text("f"),
+                text("g"),
+                text("h"),
+            ],
+        );
+    }
+
+    #[test]
+    fn merge_keeps_headings_separate() {
+        let text = |s: &str, heading_level| Paragraph {
+            text: s.encode_utf16().collect(),
+            heading_level,
+            ..Default::default()
+        };
+
+        let (blocks, paragraph_blocks) = merge_paragraphs(vec![
+            text("a", 0),
+            text("章", 1),
+            text("b", 0),
+            text("c", 0),
+        ]);
+
+        assert_eq!(paragraph_blocks, [0, 1, 2, 2]);
+        assert_eq!(
+            blocks,
+            [
+                text("a", 0).into_block(),
+                text("章", 1).into_block(),
+                text("b\nc", 0).into_block(),
+            ],
+        );
+    }
+
+    #[test]
+    fn merge_paragraphs_with_the_same_layout() {
+        let text = |s: &str, flags| Paragraph {
+            text: s.encode_utf16().collect(),
+            flags,
+            ..Default::default()
+        };
+
+        let (blocks, paragraph_blocks) = merge_paragraphs(vec![
+            text("a", 1 << 5),
+            text("b", 1 << 5),
+            text("c", 1 << 3),
+            text("d", 1 << 3 | 1),
+            text("e", 1 << 3),
+        ]);
+
+        assert_eq!(paragraph_blocks, [0, 0, 1, 2, 3]);
+        assert_eq!(
+            blocks,
+            [
+                text("a\nb", 1 << 5).into_block(),
+                text("c", 1 << 3).into_block(),
+                text("d", 1 << 3 | 1).into_block(),
+                text("e", 1 << 3).into_block(),
+            ],
+        );
+    }
+}
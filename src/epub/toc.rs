@@ -0,0 +1,260 @@
+//! Parsing of the table of contents from the EPUB 3 navigation document or the EPUB 2 NCX.
+
+use super::{
+    XmlError, get_attr, get_attr_string,
+    package::{resolve_href, split_fragment},
+    text_str, unescape,
+};
+use quick_xml::Reader;
+use std::io::BufRead;
+
+/// TocItem is an entry in the book's table of contents, before it has been matched up with the
+/// block that it points to.
+#[derive(Debug, PartialEq)]
+pub(super) struct TocItem {
+    pub(super) title: String,
+    /// level is the depth of nesting of the entry, starting at 0 for top-level entries.
+    pub(super) level: u8,
+    /// path is the path within the archive of the text file that this entry points to.
+    pub(super) path: String,
+    pub(super) fragment: Option<String>,
+}
+
+impl TocItem {
+    fn new(title: &str, level: u8, base_dir: &str, href: &str) -> Self {
+        let (_, fragment) = split_fragment(href);
+
+        Self {
+            title: title.trim().to_string(),
+            level,
+            path: resolve_href(base_dir, href),
+            fragment: fragment.map(str::to_string),
+        }
+    }
+}
+
+/// parse_nav reads the entries of the `<nav epub:type="toc">` element of an EPUB 3 navigation
+/// document.
+pub(super) fn parse_nav(nav: impl BufRead, dir: &str) -> Result<Vec<TocItem>, XmlError> {
+    let mut reader = Reader::from_reader(nav);
+    let config = reader.config_mut();
+    config.expand_empty_elements = true;
+    config.check_end_names = false;
+    config.trim_markup_names_in_closing_tags = false;
+
+    let mut toc = Vec::with_capacity(32);
+
+    let mut in_toc = false;
+    let mut list_depth: u8 = 0;
+    let mut in_reading = false;
+    // The href and title of the link being read
+    let mut link: Option<(String, String)> = None;
+
+    let mut buf = Vec::with_capacity(128);
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(quick_xml::events::Event::Start(e)) => match e.local_name().as_ref() {
+                b"nav" => {
+                    in_toc = get_attr(e.attributes(), b"type")
+                        .is_some_and(|t| t.split(|&c| c == b' ').any(|t| t == b"toc"));
+                }
+                b"ol" if in_toc => list_depth = list_depth.saturating_add(1),
+                b"a" if in_toc => {
+                    link =
+                        get_attr_string(e.attributes(), b"href").map(|href| (href, String::new()));
+                }
+                b"rt" | b"rp" => in_reading = true,
+                _ => {}
+            },
+            Ok(quick_xml::events::Event::Text(e)) => {
+                if let Some((_, ref mut title)) = link
+                    && !in_reading
+                {
+                    title.push_str(&unescape(
+                        text_str(&e).map_err(|err| XmlError::at(&reader, err))?,
+                    ));
+                }
+            }
+            Ok(quick_xml::events::Event::End(e)) => match e.local_name().as_ref() {
+                b"nav" => in_toc = false,
+                b"ol" if in_toc => list_depth = list_depth.saturating_sub(1),
+                b"a" => {
+                    if let Some((href, title)) = link.take() {
+                        toc.push(TocItem::new(
+                            &title,
+                            list_depth.saturating_sub(1),
+                            dir,
+                            &href,
+                        ));
+                    }
+                }
+                b"rt" | b"rp" => in_reading = false,
+                _ => {}
+            },
+            Err(e) => return Err(XmlError::read(&reader, e)),
+            Ok(quick_xml::events::Event::Eof) => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(toc)
+}
+
+/// parse_ncx reads the entries of the `<navMap>` of an EPUB 2 NCX document.
+pub(super) fn parse_ncx(ncx: impl BufRead, dir: &str) -> Result<Vec<TocItem>, XmlError> {
+    let mut reader = Reader::from_reader(ncx);
+    let config = reader.config_mut();
+    config.expand_empty_elements = true;
+    config.check_end_names = false;
+    config.trim_markup_names_in_closing_tags = false;
+
+    let mut toc = Vec::with_capacity(32);
+
+    let mut nav_point_depth: u8 = 0;
+    let mut in_label = false;
+    let mut in_text = false;
+    let mut title = String::new();
+
+    let mut buf = Vec::with_capacity(128);
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(quick_xml::events::Event::Start(e)) => match e.local_name().as_ref() {
+                b"navPoint" => {
+                    nav_point_depth = nav_point_depth.saturating_add(1);
+                    title.clear();
+                }
+                b"navLabel" => in_label = true,
+                b"text" if in_label => in_text = true,
+                b"content" if nav_point_depth > 0 => {
+                    if let Some(src) = get_attr_string(e.attributes(), b"src") {
+                        toc.push(TocItem::new(&title, nav_point_depth - 1, dir, &src));
+                    }
+                }
+                _ => {}
+            },
+            Ok(quick_xml::events::Event::Text(e)) if in_text => {
+                title.push_str(&unescape(
+                    text_str(&e).map_err(|err| XmlError::at(&reader, err))?,
+                ));
+            }
+            Ok(quick_xml::events::Event::End(e)) => match e.local_name().as_ref() {
+                b"navPoint" => nav_point_depth = nav_point_depth.saturating_sub(1),
+                b"navLabel" => in_label = false,
+                b"text" => in_text = false,
+                _ => {}
+            },
+            Err(e) => return Err(XmlError::read(&reader, e)),
+            Ok(quick_xml::events::Event::Eof) => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(toc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nav_toc() {
+        let nav = r#"<html><body>
+            <nav epub:type="landmarks"><ol><li><a href="cover.xhtml">Cover</a></li></ol></nav>
+            <nav epub:type="toc">
+                <ol>
+                    <li><a href="../text/p-001.xhtml">表紙</a></li>
+                    <li>
+                        <a href="../text/p-002.xhtml#c1"><ruby>第<rt>だい</rt></ruby>一章</a>
+                        <ol><li><a href="../text/p-002.xhtml#s1">一</a></li></ol>
+                    </li>
+                </ol>
+            </nav>
+        </body></html>"#;
+
+        let toc = parse_nav(nav.as_bytes(), "OEBPS/nav").unwrap();
+
+        assert_eq!(
+            toc,
+            [
+                TocItem {
+                    title: "表紙".to_string(),
+                    level: 0,
+                    path: "OEBPS/text/p-001.xhtml".to_string(),
+                    fragment: None,
+                },
+                TocItem {
+                    title: "第一章".to_string(),
+                    level: 0,
+                    path: "OEBPS/text/p-002.xhtml".to_string(),
+                    fragment: Some("c1".to_string()),
+                },
+                TocItem {
+                    title: "一".to_string(),
+                    level: 1,
+                    path: "OEBPS/text/p-002.xhtml".to_string(),
+                    fragment: Some("s1".to_string()),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_ncx_toc() {
+        let ncx = r#"<ncx><navMap>
+            <navPoint id="n1">
+                <navLabel><text>第一章</text></navLabel>
+                <content src="text/p-001.xhtml"/>
+                <navPoint id="n2">
+                    <navLabel><text>一</text></navLabel>
+                    <content src="text/p-001.xhtml#s1"/>
+                </navPoint>
+            </navPoint>
+        </navMap></ncx>"#;
+
+        let toc = parse_ncx(ncx.as_bytes(), "OEBPS").unwrap();
+
+        assert_eq!(
+            toc,
+            [
+                TocItem {
+                    title: "第一章".to_string(),
+                    level: 0,
+                    path: "OEBPS/text/p-001.xhtml".to_string(),
+                    fragment: None,
+                },
+                TocItem {
+                    title: "一".to_string(),
+                    level: 1,
+                    path: "OEBPS/text/p-001.xhtml".to_string(),
+                    fragment: Some("s1".to_string()),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_deeply_nested_toc() {
+        let nav = format!(
+            "<nav epub:type=\"toc\">{}<li><a href=\"a.xhtml\">a</a></li>{}</nav>",
+            "<ol>".repeat(300),
+            "</ol>".repeat(300),
+        );
+        let ncx = format!(
+            "<ncx><navMap>{}<navLabel><text>a</text></navLabel><content src=\"a.xhtml\"/>{}\
+             </navMap></ncx>",
+            "<navPoint>".repeat(300),
+            "</navPoint>".repeat(300),
+        );
+
+        let nav = parse_nav(nav.as_bytes(), "").unwrap();
+        let ncx = parse_ncx(ncx.as_bytes(), "").unwrap();
+
+        // The levels stop at the largest that can be stored
+        assert_eq!(nav[0].level, 254);
+        assert_eq!(ncx[0].level, 254);
+    }
+}
@@ -0,0 +1,1329 @@
+//! Reading and writing of the `.rnb` file format.
+
+mod reader;
+
+use crate::{Book, ContentBlock, Image, Metadata, Ruby, TocEntry};
+use std::{
+    char::decode_utf16,
+    fmt,
+    io::{self, Write},
+};
+
+const MAGIC: [u8; 4] = [0x89, b'R', b'N', b'B'];
+
+/// FORMAT_VERSION is the latest version of the format that can be read.
+const FORMAT_VERSION: u16 = WIDE_VERSION;
+
+/// COMPACT_VERSION stores counts, indices and offsets in as few bytes as possible, which limits a
+/// book to 255 images, 65535 blocks and table of contents entries, and 4 GiB. Books which fit are
+/// written with this version so that older readers can open them.
+const COMPACT_VERSION: u16 = 1;
+
+/// WIDE_VERSION stores counts and indices as u32, and offsets and lengths as u64.
+const WIDE_VERSION: u16 = 2;
+
+/// Widths are the sizes in bytes that a version of the format uses for the integers whose range
+/// depends on the size of the book.
+#[derive(Clone, Copy, Debug)]
+struct Widths {
+    /// num_images is the width of the number of images. Image blocks store the index of their
+    /// image in the length prefix when this is 1, and after the length prefix otherwise.
+    num_images: usize,
+    /// index is the width of the number of blocks and table of contents entries, and of the index
+    /// of a block.
+    index: usize,
+    /// offset is the width of the offsets and lengths of sections and images.
+    offset: usize,
+}
+
+impl Widths {
+    const COMPACT: Self = Self {
+        num_images: 1,
+        index: 2,
+        offset: 4,
+    };
+
+    const WIDE: Self = Self {
+        num_images: 4,
+        index: 4,
+        offset: 8,
+    };
+
+    fn of(version: u16) -> Self {
+        if version < WIDE_VERSION {
+            Self::COMPACT
+        } else {
+            Self::WIDE
+        }
+    }
+
+    /// section_entry_len is the size of the kind (u16), offset and length of a section in the
+    /// header.
+    fn section_entry_len(self) -> usize {
+        2 + 2 * self.offset
+    }
+
+    /// image_table_entry_len is the size of the offset and length of an image.
+    fn image_table_entry_len(self) -> usize {
+        2 * self.offset
+    }
+}
+
+/// SectionKind identifies the contents of a section of the file. Readers skip sections with kinds
+/// that they don't know about.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u16)]
+pub enum SectionKind {
+    Metadata = 1,
+    Toc = 2,
+    ImageTable = 3,
+    Blocks = 4,
+    ImageData = 5,
+    Continuations = 6,
+    ImageInfo = 7,
+    Headings = 8,
+    SecondaryRuby = 9,
+    Spans = 10,
+    Emphasis = 11,
+    Layout = 12,
+}
+
+impl TryFrom<u16> for SectionKind {
+    type Error = u16;
+
+    fn try_from(kind: u16) -> Result<Self, u16> {
+        match kind {
+            1 => Ok(Self::Metadata),
+            2 => Ok(Self::Toc),
+            3 => Ok(Self::ImageTable),
+            4 => Ok(Self::Blocks),
+            5 => Ok(Self::ImageData),
+            6 => Ok(Self::Continuations),
+            7 => Ok(Self::ImageInfo),
+            8 => Ok(Self::Headings),
+            9 => Ok(Self::SecondaryRuby),
+            10 => Ok(Self::Spans),
+            11 => Ok(Self::Emphasis),
+            12 => Ok(Self::Layout),
+            _ => Err(kind),
+        }
+    }
+}
+
+/// WriteError is a reason that a book couldn't be written.
+#[derive(Debug)]
+pub enum WriteError {
+    Io(io::Error),
+    /// The book has content which is too large to be encoded in the file format. The string
+    /// describes what's too large.
+    TooLarge(String),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => error.fmt(f),
+            Self::TooLarge(what) => write!(f, "too large to encode: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+/// EncodeError is a reason that a book couldn't be encoded with a particular version of the
+/// format.
+#[derive(Debug)]
+enum EncodeError {
+    /// A count, index or offset doesn't fit in the widths of the version, so the book may fit in
+    /// a wider version. The string describes what's too large.
+    Width(String),
+    Write(WriteError),
+}
+
+impl From<WriteError> for EncodeError {
+    fn from(error: WriteError) -> Self {
+        Self::Write(error)
+    }
+}
+
+impl From<EncodeError> for WriteError {
+    fn from(error: EncodeError) -> Self {
+        match error {
+            EncodeError::Width(what) => Self::TooLarge(what),
+            EncodeError::Write(error) => error,
+        }
+    }
+}
+
+pub use reader::{
+    BlockRef, Blocks, EmphasisMarks, HeadingRef, Headings, ImageInfo, Reader, RubyIter, RubyRef,
+    Spans, TextBlock, TocEntries, TocEntryRef, Utf16Str,
+};
+
+/// ReadError is a reason that a file couldn't be read.
+#[derive(Debug, PartialEq)]
+pub enum ReadError {
+    /// The file, or a section of it, ends in the middle of the value at `offset`.
+    UnexpectedEnd { offset: usize },
+    /// The string at `offset` isn't valid UTF-16.
+    InvalidString { offset: usize },
+    /// The file was written with a newer version of the format, or has a version which doesn't
+    /// exist.
+    UnsupportedVersion(u16),
+    /// A section extends past the end of the file.
+    InvalidSection { kind: u16 },
+    /// An entry in the image table points outside of the image data.
+    InvalidImage { index: usize },
+    /// An image block refers to an image which isn't in the image table.
+    InvalidImageIndex { block_idx: usize },
+    /// A ruby applies to text outside of its block.
+    InvalidRuby { block_idx: usize },
+    /// An entry in the table of contents points to a block which doesn't exist.
+    InvalidTocEntry { index: usize },
+    /// The image information doesn't have an entry for each image.
+    InvalidImageInfo,
+    /// An entry in the list of continuations points to a block which doesn't exist or isn't
+    /// text, or isn't in increasing order.
+    InvalidContinuation { index: usize },
+    /// The cover points to an image which doesn't exist.
+    InvalidCover,
+    /// A heading points to a block which doesn't exist or isn't text, isn't in increasing order,
+    /// or has a level outside of 1 to 6.
+    InvalidHeading { index: usize },
+    /// An entry in the list of secondary ruby points to a block which doesn't exist or isn't
+    /// text, isn't in increasing order, or has a ruby outside of the text of the block.
+    InvalidSecondaryRuby { index: usize },
+    /// An entry in the list of spans points to a block which doesn't exist or isn't text, isn't
+    /// in increasing order, or has a span outside of the text of the block.
+    InvalidSpans { index: usize },
+    /// An entry in the list of emphasis marks points to a block which doesn't exist or isn't
+    /// text, isn't in increasing order, or has marks outside of the text of the block.
+    InvalidEmphasis { index: usize },
+    /// An entry in the list of block layouts points to a block which doesn't exist or isn't
+    /// text, or isn't in increasing order.
+    InvalidLayout { index: usize },
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd { offset } => write!(f, "unexpected end at byte {offset}"),
+            Self::InvalidString { offset } => write!(f, "invalid UTF-16 string at byte {offset}"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported format version {version}"),
+            Self::InvalidSection { kind } => {
+                write!(f, "section of kind {kind} is outside of the file")
+            }
+            Self::InvalidImage { index } => {
+                write!(f, "image {index} is outside of the image data")
+            }
+            Self::InvalidImageIndex { block_idx } => {
+                write!(
+                    f,
+                    "block {block_idx} refers to an image which doesn't exist"
+                )
+            }
+            Self::InvalidRuby { block_idx } => {
+                write!(f, "ruby in block {block_idx} is outside of the text")
+            }
+            Self::InvalidTocEntry { index } => write!(
+                f,
+                "table of contents entry {index} points to a block which doesn't exist"
+            ),
+            Self::InvalidImageInfo => {
+                write!(f, "image information doesn't match the image metadata")
+            }
+            Self::InvalidContinuation { index } => write!(
+                f,
+                "continuation {index} points to a block which doesn't exist, isn't text or is out \
+                 of order"
+            ),
+            Self::InvalidCover => write!(f, "cover points to an image which doesn't exist"),
+            Self::InvalidHeading { index } => write!(
+                f,
+                "heading {index} points to a block which doesn't exist, isn't text or is out of \
+                 order, or has an invalid level"
+            ),
+            Self::InvalidSecondaryRuby { index } => write!(
+                f,
+                "secondary ruby {index} points to a block which doesn't exist or is out of \
+                 order, or is outside of the text"
+            ),
+            Self::InvalidSpans { index } => write!(
+                f,
+                "spans {index} point to a block which doesn't exist or is out of order, or are \
+                 outside of the text"
+            ),
+            Self::InvalidEmphasis { index } => write!(
+                f,
+                "emphasis {index} points to a block which doesn't exist or is out of order, or is \
+                 outside of the text"
+            ),
+            Self::InvalidLayout { index } => write!(
+                f,
+                "layout {index} points to a block which doesn't exist, isn't text or is out of \
+                 order"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// Header describes the layout of a file.
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub flags: u16,
+    pub sections: Vec<Section>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    /// kind is the [`SectionKind`] of the section, which may be one that this version doesn't
+    /// know about.
+    pub kind: u16,
+    /// offset is the start of the section within the entire file.
+    pub offset: u64,
+    pub length: u64,
+}
+
+// The file starts with a header:
+// - magic bytes (0x89 R N B)
+// - format version (u16)
+// - flags (u16), which are reserved and currently always 0
+// - the number of sections (u16)
+// - for each section, its kind (u16), then its start offset within the entire file (offset),
+//   then its size in bytes (offset)
+//
+// Integers whose range depends on the size of the book have a width which depends on the
+// version:
+//
+// | Integer  | Version 1 (compact) | Version 2 (wide) |
+// | -------- | ------------------- | ---------------- |
+// | offset   | u32                 | u64              |
+// | count    | u16                 | u32              |
+// | # images | u8                  | u32              |
+//
+// The sections follow the header, in any order. Sections 1 to 5 are always written. Sections 6
+// to 12 are only written when the book has something to store in them, and readers treat a
+// missing one as empty. Readers skip sections with kinds that they don't know about. Their kinds
+// are:
+//
+// 1. Metadata of the book. Strings are stored as the number of bytes (u16) followed by the
+//    UTF-16LE encoded bytes, and are empty when the book doesn't specify a value.
+// - title, language, publisher, identifier and date (strings)
+// - The number of creators (u8)
+// - each creator has 2 fields
+//   - name (string)
+//   - MARC relator code for the role of the creator, e.g. aut or ill (string)
+// - the name of the series that the book belongs to (string)
+// - the position of the book in the series (string)
+// - the index of the cover image (u32), or 0xffffffff when the book doesn't have one
+//
+// 2. Table of contents:
+// - The number of entries (count)
+// - each entry has 4 fields
+//   - the index of the block that the entry points to (count)
+//   - the level of nesting of the entry, starting at 0 for top-level entries (u8)
+//   - number of bytes for the title (u16)
+//   - UTF-16LE encoded bytes for the title
+//
+// 3. Image metadata:
+// - The number of images (# images)
+// - For each image, start offset within the image data section (offset), then size of image in
+//   bytes (offset)
+//
+// 4. Blocks:
+// - number of blocks in the book (count)
+// - then each block... Block format is:
+// - length prefix (u16 for bytes of block text)
+//   - the highest three bits are flags; from highest to lowest:
+//   - isImage: in version 1, interpret the remaining bits as an image index. In version 2, the
+//     remaining bits are 0 and the image index (u32) follows the prefix.
+//   - isLarge: display the paragraph with larger text
+//   - isBold: display the paragraph with bold text
+//
+// - text of block (UTF-16LE)
+// - list of spans
+//   - num furigana spans (u8)
+//   - each span has 4 fields
+//   - the start offset of where it applies to the text (u16)
+//   - the number of chars it applies to in the text (u8)
+//   - number of bytes for the reading (u8)
+//   - UTF-16LE encoded bytes for reading
+//
+// 5. Image data, one image after the next.
+//
+// 6. Continuations:
+// - The number of continuations (count)
+// - for each continuation, the index of a text block which continues the last paragraph of the
+//   previous block (count), in increasing order
+//
+// 7. Image information:
+// - The number of images (# images)
+// - for each image, in the same order as the image metadata:
+//   - the format of the image (u8): 0 for unknown, 1 for JPEG, 2 for PNG, 3 for GIF, 4 for WebP,
+//     5 for SVG or 6 for AVIF
+//   - the width, then the height of the image in pixels (u32 each), which are 0 when the size
+//     isn't known
+//
+// 8. Headings:
+// - The number of headings (count)
+// - for each heading, in increasing order of block index:
+//   - the index of the text block which is a heading (count)
+//   - the level of the heading (u8), from 1 for `<h1>` to 6 for `<h6>`
+//
+// 9. Secondary ruby, the annotations from `<rtc>` which are shown on the other side of the text:
+// - The number of text blocks with secondary ruby (count)
+// - for each of these blocks, in increasing order of block index:
+//   - the index of the block (count)
+//   - the ruby, in the same layout as the ruby of a block
+//
+// 10. Spans, the inline formatting of parts of the text of blocks:
+// - The number of text blocks with spans (count)
+// - for each of these blocks, in increasing order of block index:
+//   - the index of the block (count)
+//   - the number of spans (u16)
+//   - each span has 3 fields, in increasing order of start offset
+//     - the start offset of where it applies to the text (u16)
+//     - the number of UTF-16 code units it applies to in the text (u16)
+//     - the style (u8): 1 for bold, 2 for italic, 3 for larger text, 4 for smaller text or 5 for
+//       text which is set upright in vertical text (縦中横)
+//
+// 11. Emphasis marks (傍点) beside parts of the text of blocks:
+// - the same layout as the spans, where the style (u8) is the shape of the marks: 1 for filled
+//   sesame, 2 for open sesame, 3 for filled dot, 4 for open dot, 5 for filled circle, 6 for open
+//   circle, 7 for filled double circle, 8 for open double circle, 9 for filled triangle or 10
+//   for open triangle
+//
+// 12. Layout of the paragraphs of blocks:
+// - The number of text blocks with a layout (count)
+// - for each of these blocks, in increasing order of block index:
+//   - the index of the block (count)
+//   - the layout flags (u8); from highest to lowest:
+//     - isVertical: the text is vertical
+//     - isHangingIndent: the lines after the first line of each paragraph are indented
+//     - isIndented: the first line of each paragraph is indented
+//     - isEndAligned: the text is aligned to the end of the line
+//     - isCentred: the text is centred
+//     - the lowest three bits are 0
+
+/// write stores `book` in the `.rnb` format, using the compact version of the format when the
+/// book fits in it.
+pub fn write(book: &Book, mut out: impl Write) -> Result<(), WriteError> {
+    // Nothing is written until the book has been encoded, so a book which is too large for the
+    // compact version can be written again with the wide version.
+    match write_version(book, COMPACT_VERSION, &mut out) {
+        Err(EncodeError::Width(_)) => write_version(book, WIDE_VERSION, out),
+        result => result,
+    }
+    .map_err(WriteError::from)
+}
+
+fn write_version(book: &Book, version: u16, mut out: impl Write) -> Result<(), EncodeError> {
+    let widths = Widths::of(version);
+
+    let mut metadata_section = Vec::with_capacity(256);
+    extend_with_metadata(&mut metadata_section, &book.metadata)?;
+
+    let mut toc_section = Vec::with_capacity(1024);
+    extend_with_toc(&mut toc_section, &book.toc, widths)?;
+
+    let mut image_table_section = Vec::with_capacity(256);
+    let (image_offsets, image_data_len) = image_offsets(&book.images)?;
+    extend_with_image_meta(
+        &mut image_table_section,
+        &image_offsets,
+        &book.images,
+        widths,
+    )?;
+
+    let mut image_info_section = Vec::with_capacity(64);
+    extend_with_image_info(&mut image_info_section, &book.images, widths)?;
+
+    let mut blocks_section = Vec::with_capacity(1 << 18);
+    extend_with_blocks(&mut blocks_section, &book.blocks, widths)?;
+
+    let mut continuations_section = Vec::new();
+    extend_with_continuations(&mut continuations_section, &book.blocks, widths)?;
+
+    let mut headings_section = Vec::new();
+    extend_with_headings(&mut headings_section, &book.blocks, widths)?;
+
+    let mut layout_section = Vec::new();
+    extend_with_layout(&mut layout_section, &book.blocks, widths)?;
+
+    let mut secondary_ruby_section = Vec::new();
+    extend_with_secondary_ruby(&mut secondary_ruby_section, &book.blocks, widths)?;
+
+    let mut spans_section = Vec::new();
+    extend_with_span_lists(
+        &mut spans_section,
+        &book.blocks,
+        widths,
+        "spans",
+        |block| match block {
+            ContentBlock::Text { spans, .. } => spans
+                .iter()
+                .map(|span| (span.start_offset, span.length, span.style as u8))
+                .collect(),
+            ContentBlock::Image { .. } => Vec::new(),
+        },
+    )?;
+
+    let mut emphasis_section = Vec::new();
+    extend_with_span_lists(
+        &mut emphasis_section,
+        &book.blocks,
+        widths,
+        "emphasis",
+        |block| match block {
+            ContentBlock::Text { emphasis, .. } => emphasis
+                .iter()
+                .map(|emphasis| (emphasis.start_offset, emphasis.length, emphasis.style as u8))
+                .collect(),
+            ContentBlock::Image { .. } => Vec::new(),
+        },
+    )?;
+
+    let mut sections = vec![
+        (SectionKind::Metadata, metadata_section.len() as u64),
+        (SectionKind::Toc, toc_section.len() as u64),
+        (SectionKind::ImageTable, image_table_section.len() as u64),
+    ];
+    if !image_info_section.is_empty() {
+        sections.push((SectionKind::ImageInfo, image_info_section.len() as u64));
+    }
+    sections.push((SectionKind::Blocks, blocks_section.len() as u64));
+    if !continuations_section.is_empty() {
+        sections.push((
+            SectionKind::Continuations,
+            continuations_section.len() as u64,
+        ));
+    }
+    if !headings_section.is_empty() {
+        sections.push((SectionKind::Headings, headings_section.len() as u64));
+    }
+    if !secondary_ruby_section.is_empty() {
+        sections.push((
+            SectionKind::SecondaryRuby,
+            secondary_ruby_section.len() as u64,
+        ));
+    }
+    if !spans_section.is_empty() {
+        sections.push((SectionKind::Spans, spans_section.len() as u64));
+    }
+    if !emphasis_section.is_empty() {
+        sections.push((SectionKind::Emphasis, emphasis_section.len() as u64));
+    }
+    if !layout_section.is_empty() {
+        sections.push((SectionKind::Layout, layout_section.len() as u64));
+    }
+    sections.push((SectionKind::ImageData, image_data_len));
+
+    let mut buf = Vec::with_capacity(
+        metadata_section.len()
+            + toc_section.len()
+            + image_table_section.len()
+            + image_info_section.len()
+            + blocks_section.len()
+            + continuations_section.len()
+            + headings_section.len()
+            + secondary_ruby_section.len()
+            + spans_section.len()
+            + emphasis_section.len()
+            + layout_section.len()
+            + 128,
+    );
+    extend_with_header(&mut buf, version, &sections)?;
+
+    buf.extend_from_slice(&metadata_section);
+    buf.extend_from_slice(&toc_section);
+    buf.extend_from_slice(&image_table_section);
+    buf.extend_from_slice(&image_info_section);
+    buf.extend_from_slice(&blocks_section);
+    buf.extend_from_slice(&continuations_section);
+    buf.extend_from_slice(&headings_section);
+    buf.extend_from_slice(&secondary_ruby_section);
+    buf.extend_from_slice(&spans_section);
+    buf.extend_from_slice(&emphasis_section);
+    buf.extend_from_slice(&layout_section);
+
+    out.write_all(&buf).map_err(WriteError::Io)?;
+
+    for image in &book.images {
+        out.write_all(&image.data).map_err(WriteError::Io)?;
+    }
+
+    Ok(())
+}
+
+/// encoded_len converts `len` to the type that's used to store it in the file, failing when it's
+/// too large. `what` describes the value for the error message.
+fn encoded_len<T: TryFrom<usize>>(len: usize, what: &str) -> Result<T, WriteError> {
+    T::try_from(len).map_err(|_| WriteError::TooLarge(format!("{what} ({len})")))
+}
+
+/// extend_with_uint writes `value` as a little-endian integer which is `width` bytes wide,
+/// failing when it doesn't fit. `what` describes the value for the error message.
+fn extend_with_uint(
+    buf: &mut Vec<u8>,
+    value: u64,
+    width: usize,
+    what: &str,
+) -> Result<(), EncodeError> {
+    if !fits(value, width) {
+        return Err(EncodeError::Width(format!("{what} ({value})")));
+    }
+
+    buf.extend_from_slice(&value.to_le_bytes()[..width]);
+
+    Ok(())
+}
+
+/// fits returns whether `value` can be stored in an integer which is `width` bytes wide.
+fn fits(value: u64, width: usize) -> bool {
+    width >= 8 || value >> (width * 8) == 0
+}
+
+/// extend_with_header writes the header for sections which will be written in the given order,
+/// directly after the header.
+fn extend_with_header(
+    buf: &mut Vec<u8>,
+    version: u16,
+    sections: &[(SectionKind, u64)],
+) -> Result<(), EncodeError> {
+    let widths = Widths::of(version);
+
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&version.to_le_bytes());
+
+    let flags: u16 = 0;
+    buf.extend_from_slice(&flags.to_le_bytes());
+
+    let num_sections: u16 = encoded_len(sections.len(), "number of sections")?;
+    buf.extend_from_slice(&num_sections.to_le_bytes());
+
+    let header_len = buf.len() + sections.len() * widths.section_entry_len();
+
+    let mut offset = header_len as u64;
+    for &(kind, len) in sections {
+        buf.extend_from_slice(&(kind as u16).to_le_bytes());
+        extend_with_uint(buf, offset, widths.offset, "file")?;
+        extend_with_uint(buf, len, widths.offset, "section")?;
+
+        offset = offset
+            .checked_add(len)
+            .ok_or_else(|| WriteError::TooLarge("file".to_string()))?;
+    }
+
+    // The end of the last section has to fit too, since readers check it
+    if !fits(offset, widths.offset) {
+        return Err(EncodeError::Width(format!("file ({offset} bytes)")));
+    }
+
+    Ok(())
+}
+
+fn extend_with_blocks(
+    buf: &mut Vec<u8>,
+    blocks: &[ContentBlock],
+    widths: Widths,
+) -> Result<(), EncodeError> {
+    extend_with_uint(buf, blocks.len() as u64, widths.index, "number of blocks")?;
+
+    for block in blocks {
+        match *block {
+            ContentBlock::Text {
+                ref text,
+                ref ruby,
+                flags,
+                ..
+            } => {
+                let num_text_bytes = text.len() * 2;
+                if num_text_bytes >= 1 << 13 {
+                    return Err(WriteError::TooLarge(format!(
+                        "block text is too long: `{}`",
+                        decode_utf16(text.iter().copied())
+                            .map(|res| res.unwrap_or(char::REPLACEMENT_CHARACTER))
+                            .collect::<String>(),
+                    ))
+                    .into());
+                }
+
+                let mut len_prefix = num_text_bytes as u16;
+
+                // Use the second-highest and third-highest bits for formatting info. 10
+                // bits is probably sufficient for block length (in bytes). Proposal
+                // is to use two additional bits, leaving 13 bits for the length. 2^13 =
+                // 8192 or 4096 chars.
+                // Only the lowest two bits are stored here, so these flags won't conflict with
+                // the flag for image indices. The flag for continuations and the flags for the
+                // layout are stored in their own sections.
+                len_prefix |= u16::from(flags & 0b11) << 13;
+
+                buf.extend_from_slice(&len_prefix.to_le_bytes());
+                if num_text_bytes == 0 {
+                    continue;
+                }
+
+                buf.extend(text.iter().flat_map(|ch| ch.to_le_bytes()));
+
+                extend_with_ruby(buf, ruby)?;
+            }
+            ContentBlock::Image { index } if widths.num_images == 1 => {
+                // There are at most 255 images, so the index fits in the prefix
+                let index = u8::try_from(index)
+                    .map_err(|_| EncodeError::Width(format!("image index ({index})")))?;
+                let image_idx_or_len_prefix: u16 = u16::from(index) | (1 << 15);
+                buf.extend_from_slice(&image_idx_or_len_prefix.to_le_bytes());
+            }
+            ContentBlock::Image { index } => {
+                let len_prefix: u16 = 1 << 15;
+                buf.extend_from_slice(&len_prefix.to_le_bytes());
+                extend_with_uint(buf, index.into(), widths.num_images, "image index")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// extend_with_continuations writes the indices of the text blocks which continue the previous
+/// block. Nothing is written when there aren't any.
+fn extend_with_continuations(
+    buf: &mut Vec<u8>,
+    blocks: &[ContentBlock],
+    widths: Widths,
+) -> Result<(), EncodeError> {
+    let continuations = blocks
+        .iter()
+        .enumerate()
+        .filter(
+            |(_, block)| matches!(block, ContentBlock::Text { flags, .. } if flags & 1 << 2 != 0),
+        )
+        .map(|(i, _)| i as u64)
+        .collect::<Vec<_>>();
+    if continuations.is_empty() {
+        return Ok(());
+    }
+
+    extend_with_uint(
+        buf,
+        continuations.len() as u64,
+        widths.index,
+        "number of continuations",
+    )?;
+    for block_idx in continuations {
+        extend_with_uint(buf, block_idx, widths.index, "continuation block index")?;
+    }
+
+    Ok(())
+}
+
+/// extend_with_headings writes the indices and levels of the text blocks which are headings.
+/// Nothing is written when there aren't any.
+fn extend_with_headings(
+    buf: &mut Vec<u8>,
+    blocks: &[ContentBlock],
+    widths: Widths,
+) -> Result<(), EncodeError> {
+    let headings = blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, block)| match *block {
+            ContentBlock::Text { heading_level, .. } if heading_level != 0 => {
+                Some((i as u64, heading_level))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if headings.is_empty() {
+        return Ok(());
+    }
+
+    extend_with_uint(
+        buf,
+        headings.len() as u64,
+        widths.index,
+        "number of headings",
+    )?;
+    for (block_idx, level) in headings {
+        debug_assert!(level <= 6, "invalid heading level: {level}");
+
+        extend_with_uint(buf, block_idx, widths.index, "heading block index")?;
+        buf.push(level);
+    }
+
+    Ok(())
+}
+
+/// extend_with_layout writes the layout flags of the text blocks which have any. Nothing is
+/// written when there aren't any.
+fn extend_with_layout(
+    buf: &mut Vec<u8>,
+    blocks: &[ContentBlock],
+    widths: Widths,
+) -> Result<(), EncodeError> {
+    let laid_out = blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, block)| match *block {
+            ContentBlock::Text { flags, .. } if flags & !0b111 != 0 => {
+                Some((i as u64, flags & !0b111))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if laid_out.is_empty() {
+        return Ok(());
+    }
+
+    extend_with_uint(
+        buf,
+        laid_out.len() as u64,
+        widths.index,
+        "number of blocks with a layout",
+    )?;
+    for (block_idx, layout) in laid_out {
+        extend_with_uint(buf, block_idx, widths.index, "layout block index")?;
+        buf.push(layout);
+    }
+
+    Ok(())
+}
+
+/// extend_with_secondary_ruby writes the secondary ruby of the text blocks which have any. Nothing
+/// is written when there aren't any.
+fn extend_with_secondary_ruby(
+    buf: &mut Vec<u8>,
+    blocks: &[ContentBlock],
+    widths: Widths,
+) -> Result<(), EncodeError> {
+    let annotated = blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, block)| match block {
+            ContentBlock::Text { secondary_ruby, .. } if !secondary_ruby.is_empty() => {
+                Some((i as u64, secondary_ruby))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if annotated.is_empty() {
+        return Ok(());
+    }
+
+    extend_with_uint(
+        buf,
+        annotated.len() as u64,
+        widths.index,
+        "number of blocks with secondary ruby",
+    )?;
+    for (block_idx, secondary_ruby) in annotated {
+        extend_with_uint(buf, block_idx, widths.index, "secondary ruby block index")?;
+        extend_with_ruby(buf, secondary_ruby)?;
+    }
+
+    Ok(())
+}
+
+/// extend_with_span_lists writes the spans that `spans_of` returns for each text block, as
+/// (start offset, length, style). Blocks without any are left out, and nothing is written when
+/// none of the blocks have any. `what` describes the spans for error messages.
+fn extend_with_span_lists(
+    buf: &mut Vec<u8>,
+    blocks: &[ContentBlock],
+    widths: Widths,
+    what: &str,
+    spans_of: impl Fn(&ContentBlock) -> Vec<(u16, u16, u8)>,
+) -> Result<(), EncodeError> {
+    let lists = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (i as u64, spans_of(block)))
+        .filter(|(_, spans)| !spans.is_empty())
+        .collect::<Vec<_>>();
+    if lists.is_empty() {
+        return Ok(());
+    }
+
+    extend_with_uint(
+        buf,
+        lists.len() as u64,
+        widths.index,
+        &format!("number of blocks with {what}"),
+    )?;
+    let block_idx_what = format!("{what} block index");
+    let num_spans_what = format!("number of {what} in a block");
+    for (block_idx, spans) in lists {
+        extend_with_uint(buf, block_idx, widths.index, &block_idx_what)?;
+
+        let num_spans: u16 = encoded_len(spans.len(), &num_spans_what)?;
+        buf.extend_from_slice(&num_spans.to_le_bytes());
+        for (start_offset, length, style) in spans {
+            buf.extend_from_slice(&start_offset.to_le_bytes());
+            buf.extend_from_slice(&length.to_le_bytes());
+            buf.push(style);
+        }
+    }
+
+    Ok(())
+}
+
+fn extend_with_ruby(buf: &mut Vec<u8>, ruby: &[Ruby]) -> Result<(), WriteError> {
+    // Write num furigana spans (u8)
+    buf.push(encoded_len(ruby.len(), "number of ruby in a block")?);
+
+    for r in ruby {
+        buf.extend_from_slice(&r.start_offset.to_le_bytes());
+
+        buf.push(r.length);
+
+        let num_reading_bytes: u8 = encoded_len(r.reading.len() * 2, "ruby reading")?;
+        buf.push(num_reading_bytes);
+
+        buf.extend(r.reading.iter().flat_map(|ch| ch.to_le_bytes()));
+    }
+
+    Ok(())
+}
+
+fn extend_with_toc(buf: &mut Vec<u8>, toc: &[TocEntry], widths: Widths) -> Result<(), EncodeError> {
+    extend_with_uint(
+        buf,
+        toc.len() as u64,
+        widths.index,
+        "number of table of contents entries",
+    )?;
+
+    for entry in toc {
+        extend_with_uint(
+            buf,
+            entry.block_idx.into(),
+            widths.index,
+            "table of contents block index",
+        )?;
+
+        buf.push(entry.level);
+
+        extend_with_string(buf, &entry.title)?;
+    }
+
+    Ok(())
+}
+
+fn extend_with_metadata(buf: &mut Vec<u8>, metadata: &Metadata) -> Result<(), WriteError> {
+    extend_with_string(buf, &metadata.title)?;
+    extend_with_string(buf, &metadata.language)?;
+    extend_with_string(buf, &metadata.publisher)?;
+    extend_with_string(buf, &metadata.identifier)?;
+    extend_with_string(buf, &metadata.date)?;
+
+    buf.push(encoded_len(metadata.creators.len(), "number of creators")?);
+    for creator in &metadata.creators {
+        extend_with_string(buf, &creator.name)?;
+        extend_with_string(buf, &creator.role)?;
+    }
+
+    let series = metadata.series.as_ref();
+    extend_with_string(buf, series.map(|s| s.name.as_str()).unwrap_or_default())?;
+    extend_with_string(buf, series.map(|s| s.index.as_str()).unwrap_or_default())?;
+
+    buf.extend_from_slice(&metadata.cover_image.unwrap_or(u32::MAX).to_le_bytes());
+    Ok(())
+}
+
+/// extend_with_string writes `s` prefixed by its length in bytes (u16).
+fn extend_with_string(buf: &mut Vec<u8>, s: &str) -> Result<(), WriteError> {
+    let len_offset = buf.len();
+    buf.extend_from_slice(&[0, 0]);
+
+    buf.extend(s.encode_utf16().flat_map(|ch| ch.to_le_bytes()));
+
+    let num_bytes: u16 = encoded_len(buf.len() - len_offset - 2, "string")?;
+    buf[len_offset..len_offset + 2].copy_from_slice(&num_bytes.to_le_bytes());
+
+    Ok(())
+}
+
+/// image_offsets returns the offset of each image within the image data, and the total size of
+/// the image data.
+fn image_offsets(images: &[Image]) -> Result<(Box<[u64]>, u64), WriteError> {
+    let mut total: u64 = 0;
+    let offsets = images
+        .iter()
+        .map(|image| {
+            let offset = total;
+            total = total
+                .checked_add(image.data.len() as u64)
+                .ok_or_else(|| WriteError::TooLarge("images".to_string()))?;
+
+            Ok(offset)
+        })
+        .collect::<Result<Box<[_]>, WriteError>>()?;
+
+    Ok((offsets, total))
+}
+
+fn extend_with_image_meta(
+    buf: &mut Vec<u8>,
+    image_offsets: &[u64],
+    images: &[Image],
+    widths: Widths,
+) -> Result<(), EncodeError> {
+    extend_with_uint(
+        buf,
+        image_offsets.len() as u64,
+        widths.num_images,
+        "number of images",
+    )?;
+
+    for (&offset, image) in image_offsets.iter().zip(images) {
+        extend_with_uint(buf, offset, widths.offset, "image offset")?;
+        extend_with_uint(buf, image.data.len() as u64, widths.offset, "image")?;
+    }
+
+    Ok(())
+}
+
+/// extend_with_image_info writes the format and size of each image. Nothing is written when there
+/// aren't any images.
+fn extend_with_image_info(
+    buf: &mut Vec<u8>,
+    images: &[Image],
+    widths: Widths,
+) -> Result<(), EncodeError> {
+    if images.is_empty() {
+        return Ok(());
+    }
+
+    extend_with_uint(
+        buf,
+        images.len() as u64,
+        widths.num_images,
+        "number of images",
+    )?;
+
+    for image in images {
+        buf.push(image.format as u8);
+        buf.extend_from_slice(&image.width.to_le_bytes());
+        buf.extend_from_slice(&image.height.to_le_bytes());
+    }
+
+    Ok(())
+}
+
+/// read loads a book which was stored with [`write()`]. Files from before the header was added can
+/// be read too, but they only contain blocks and images. Use [`Reader`] to access the contents
+/// without copying them.
+pub fn read(bytes: &[u8]) -> Result<Book, ReadError> {
+    let reader = Reader::new(bytes)?;
+
+    Ok(Book {
+        metadata: reader.metadata(),
+        toc: reader.toc().map(TocEntry::from).collect(),
+        blocks: reader.blocks().map(ContentBlock::from).collect(),
+        images: reader
+            .images()
+            .enumerate()
+            .map(|(i, data)| {
+                let info = reader.image_info(i);
+                Image {
+                    data: data.into(),
+                    format: info.format,
+                    width: info.width,
+                    height: info.height,
+                }
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Creator, Emphasis, EmphasisStyle, ImageFormat, Series, SpanStyle, StyleSpan};
+
+    #[test]
+    fn block_too_long() {
+        let block = ContentBlock::Text {
+            text: vec![u16::from(b'a'); 4096].into_boxed_slice(),
+            ruby: Box::new([]),
+            secondary_ruby: Box::new([]),
+            spans: Box::new([]),
+            emphasis: Box::new([]),
+            flags: 0,
+            heading_level: 0,
+        };
+
+        let error = extend_with_blocks(&mut Vec::new(), &[block], Widths::WIDE).unwrap_err();
+
+        // The wide version has the same limit, so trying it wouldn't help
+        assert!(
+            matches!(error, EncodeError::Write(WriteError::TooLarge(_))),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn header_section_offsets() {
+        let mut buf = Vec::new();
+
+        extend_with_header(
+            &mut buf,
+            COMPACT_VERSION,
+            &[(SectionKind::Blocks, 3), (SectionKind::ImageData, 5)],
+        )
+        .unwrap();
+
+        assert_eq!(buf.len(), 30);
+        assert_eq!(buf[..4], MAGIC);
+        assert_eq!(buf[4..6], COMPACT_VERSION.to_le_bytes());
+
+        // The first section starts right after the header, and the second one after that.
+        assert_eq!(buf[10..12], (SectionKind::Blocks as u16).to_le_bytes());
+        assert_eq!(buf[12..16], 30u32.to_le_bytes());
+        assert_eq!(buf[16..20], 3u32.to_le_bytes());
+        assert_eq!(buf[22..26], 33u32.to_le_bytes());
+    }
+
+    #[test]
+    fn wide_header_section_offsets() {
+        let mut buf = Vec::new();
+
+        extend_with_header(
+            &mut buf,
+            WIDE_VERSION,
+            &[(SectionKind::Blocks, 3), (SectionKind::ImageData, 5)],
+        )
+        .unwrap();
+
+        assert_eq!(buf.len(), 46);
+        assert_eq!(buf[4..6], WIDE_VERSION.to_le_bytes());
+
+        assert_eq!(buf[12..20], 46u64.to_le_bytes());
+        assert_eq!(buf[20..28], 3u64.to_le_bytes());
+        assert_eq!(buf[30..38], 49u64.to_le_bytes());
+    }
+
+    #[test]
+    fn file_too_large_for_compact_version() {
+        let error = extend_with_header(
+            &mut Vec::new(),
+            COMPACT_VERSION,
+            &[(SectionKind::ImageData, u64::from(u32::MAX))],
+        )
+        .unwrap_err();
+
+        assert!(matches!(error, EncodeError::Width(_)), "{error:?}");
+    }
+
+    #[test]
+    fn write_then_read() {
+        let book = Book {
+            metadata: Metadata {
+                title: "本".to_string(),
+                creators: vec![Creator {
+                    name: "山田太郎".to_string(),
+                    role: "aut".to_string(),
+                }],
+                language: "ja".to_string(),
+                series: Some(Series {
+                    name: "シリーズ".to_string(),
+                    index: "2".to_string(),
+                }),
+                cover_image: Some(1),
+                ..Default::default()
+            },
+            toc: vec![TocEntry {
+                title: "第一章".to_string(),
+                level: 0,
+                block_idx: 1,
+            }],
+            blocks: vec![
+                ContentBlock::Image { index: 1 },
+                ContentBlock::Text {
+                    text: "開発\nb".encode_utf16().collect(),
+                    ruby: Box::new([Ruby {
+                        start_offset: 0,
+                        length: 2,
+                        reading: "かいはつ".encode_utf16().collect(),
+                    }]),
+                    secondary_ruby: Box::new([Ruby {
+                        start_offset: 0,
+                        length: 2,
+                        reading: "development".encode_utf16().collect(),
+                    }]),
+                    spans: Box::new([
+                        StyleSpan {
+                            start_offset: 0,
+                            length: 4,
+                            style: SpanStyle::Italic,
+                        },
+                        StyleSpan {
+                            start_offset: 3,
+                            length: 1,
+                            style: SpanStyle::Smaller,
+                        },
+                    ]),
+                    emphasis: Box::new([Emphasis {
+                        start_offset: 0,
+                        length: 2,
+                        style: EmphasisStyle::OpenCircle,
+                    }]),
+                    flags: 1,
+                    heading_level: 0,
+                },
+                ContentBlock::Text {
+                    text: Box::new([]),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([]),
+                    flags: 0,
+                    heading_level: 0,
+                },
+                ContentBlock::Text {
+                    text: "続き".encode_utf16().collect(),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([]),
+                    flags: 1 << 7 | 1 << 5 | 1 << 2 | 1,
+                    heading_level: 0,
+                },
+                ContentBlock::Text {
+                    text: "第二章".encode_utf16().collect(),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([Ruby {
+                        start_offset: 0,
+                        length: 3,
+                        reading: "chapter 2".encode_utf16().collect(),
+                    }]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([]),
+                    flags: 0,
+                    heading_level: 2,
+                },
+            ],
+            images: vec![
+                Image {
+                    data: Box::new([1, 2, 3]),
+                    format: ImageFormat::Png,
+                    width: 1,
+                    height: 3,
+                },
+                Image {
+                    data: Box::new([4, 5]),
+                    format: ImageFormat::Avif,
+                    width: 0,
+                    height: 0,
+                },
+            ],
+        };
+
+        let mut buf = Vec::new();
+        write(&book, &mut buf).unwrap();
+
+        assert_eq!(read(&buf).unwrap(), book);
+        let reader = Reader::new(&buf).unwrap();
+        assert_eq!(reader.cover_image(), Some(&[4, 5][..]));
+        assert_eq!(
+            reader.headings().collect::<Vec<_>>(),
+            [HeadingRef {
+                block_idx: 4,
+                level: 2,
+            }],
+        );
+    }
+
+    #[test]
+    fn small_books_use_compact_version() {
+        let mut buf = Vec::new();
+        write(&Book::default(), &mut buf).unwrap();
+
+        assert_eq!(buf[4..6], COMPACT_VERSION.to_le_bytes());
+    }
+
+    #[test]
+    fn write_then_read_many_images() {
+        let book = Book {
+            toc: vec![TocEntry {
+                title: "最後".to_string(),
+                level: 0,
+                block_idx: 299,
+            }],
+            blocks: (0..300)
+                .map(|index| ContentBlock::Image { index })
+                .collect(),
+            images: (0..300)
+                .map(|i| Image {
+                    data: Box::new([i as u8, (i >> 8) as u8]),
+                    format: ImageFormat::Jpeg,
+                    width: i,
+                    height: 1,
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let mut buf = Vec::new();
+        write(&book, &mut buf).unwrap();
+
+        assert_eq!(buf[4..6], WIDE_VERSION.to_le_bytes());
+        assert_eq!(read(&buf).unwrap(), book);
+    }
+
+    #[test]
+    fn read_legacy_file() {
+        let mut buf = Vec::new();
+        // 1 block, 1 image
+        buf.extend_from_slice(&1u16.to_le_bytes());
+        buf.push(1);
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        // a bold block with the text "a" and no ruby
+        buf.extend_from_slice(&(2u16 | 1 << 13).to_le_bytes());
+        buf.extend_from_slice(&u16::from(b'a').to_le_bytes());
+        buf.push(0);
+        buf.extend_from_slice(&[8, 9]);
+
+        let book = read(&buf).unwrap();
+
+        assert_eq!(
+            book.blocks,
+            [ContentBlock::Text {
+                text: Box::new([u16::from(b'a')]),
+                ruby: Box::new([]),
+                secondary_ruby: Box::new([]),
+                spans: Box::new([]),
+                emphasis: Box::new([]),
+                flags: 1,
+                heading_level: 0,
+            }],
+        );
+        assert_eq!(
+            book.images,
+            [Image {
+                data: Box::new([8, 9]),
+                format: ImageFormat::Unknown,
+                width: 0,
+                height: 0,
+            }],
+        );
+    }
+
+    #[test]
+    fn read_truncated() {
+        let mut buf = Vec::new();
+        write(&Book::default(), &mut buf).unwrap();
+
+        assert_eq!(
+            read(&buf[..buf.len() - 1]),
+            Err(ReadError::InvalidSection {
+                kind: SectionKind::Blocks as u16,
+            }),
+        );
+    }
+}
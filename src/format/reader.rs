@@ -0,0 +1,1835 @@
+//! Reading of `.rnb` files without copying them, for files which may be corrupt or malicious.
+
+use super::{FORMAT_VERSION, Header, MAGIC, ReadError, Section, SectionKind, Widths};
+use crate::{
+    ContentBlock, Creator, Emphasis, ImageFormat, Metadata, Ruby, Series, StyleSpan, TocEntry,
+};
+use std::{char::decode_utf16, fmt};
+
+/// Reader provides access to the contents of a `.rnb` file which borrows from the file's bytes,
+/// e.g. from a memory map.
+///
+/// The whole file is validated when the reader is created, so none of the accessors can fail
+/// afterwards, and they never read outside of the file.
+pub struct Reader<'a> {
+    header: Option<Header>,
+    widths: Widths,
+    metadata: Option<Cursor<'a>>,
+    /// toc is positioned at the first entry.
+    toc: Cursor<'a>,
+    num_toc_entries: u32,
+    /// image_table is positioned at the first entry.
+    image_table: Cursor<'a>,
+    num_images: u32,
+    /// image_info is positioned at the first entry, and is empty for files which don't have it.
+    image_info: Cursor<'a>,
+    num_image_info: u32,
+    image_data: &'a [u8],
+    /// blocks is positioned at the first block.
+    blocks: Cursor<'a>,
+    num_blocks: u32,
+    /// continuations is positioned at the index of the first block which continues the previous
+    /// one.
+    continuations: Cursor<'a>,
+    num_continuations: u32,
+    /// headings is positioned at the first heading.
+    headings: Cursor<'a>,
+    num_headings: u32,
+    /// secondary_ruby is positioned at the first block with secondary ruby.
+    secondary_ruby: Cursor<'a>,
+    num_secondary_ruby_blocks: u32,
+    /// spans is positioned at the first block with spans.
+    spans: Cursor<'a>,
+    num_span_blocks: u32,
+    /// emphasis is positioned at the first block with emphasis marks.
+    emphasis: Cursor<'a>,
+    num_emphasis_blocks: u32,
+    /// layout is positioned at the first block with a layout.
+    layout: Cursor<'a>,
+    num_layout_blocks: u32,
+}
+
+impl<'a> Reader<'a> {
+    /// new checks that `bytes` is a well-formed `.rnb` file. Files from before the header was
+    /// added are supported too, but they only contain blocks and images.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, ReadError> {
+        let reader = match read_header(bytes)? {
+            Some(header) => Self::from_sections(bytes, header)?,
+            None => Self::legacy(bytes)?,
+        };
+
+        reader.validate()?;
+
+        Ok(reader)
+    }
+
+    fn empty(bytes: &'a [u8]) -> Self {
+        Self {
+            header: None,
+            widths: Widths::COMPACT,
+            metadata: None,
+            toc: Cursor::empty(bytes),
+            num_toc_entries: 0,
+            image_table: Cursor::empty(bytes),
+            num_images: 0,
+            image_info: Cursor::empty(bytes),
+            num_image_info: 0,
+            image_data: &[],
+            blocks: Cursor::empty(bytes),
+            num_blocks: 0,
+            continuations: Cursor::empty(bytes),
+            num_continuations: 0,
+            headings: Cursor::empty(bytes),
+            num_headings: 0,
+            secondary_ruby: Cursor::empty(bytes),
+            num_secondary_ruby_blocks: 0,
+            spans: Cursor::empty(bytes),
+            num_span_blocks: 0,
+            emphasis: Cursor::empty(bytes),
+            num_emphasis_blocks: 0,
+            layout: Cursor::empty(bytes),
+            num_layout_blocks: 0,
+        }
+    }
+
+    fn from_sections(bytes: &'a [u8], header: Header) -> Result<Self, ReadError> {
+        let mut reader = Self::empty(bytes);
+        reader.widths = Widths::of(header.version);
+        let widths = reader.widths;
+
+        for section in &header.sections {
+            let mut cursor = Cursor::section(bytes, section)
+                .ok_or(ReadError::InvalidSection { kind: section.kind })?;
+
+            match SectionKind::try_from(section.kind) {
+                Ok(SectionKind::Metadata) => reader.metadata = Some(cursor),
+                Ok(SectionKind::Toc) => {
+                    reader.num_toc_entries = cursor.uint(widths.index)? as u32;
+                    reader.toc = cursor;
+                }
+                Ok(SectionKind::ImageTable) => {
+                    reader.num_images = cursor.uint(widths.num_images)? as u32;
+                    reader.image_table = cursor;
+                }
+                Ok(SectionKind::Blocks) => {
+                    reader.num_blocks = cursor.uint(widths.index)? as u32;
+                    reader.blocks = cursor;
+                }
+                Ok(SectionKind::ImageInfo) => {
+                    reader.num_image_info = cursor.uint(widths.num_images)? as u32;
+                    reader.image_info = cursor;
+                }
+                Ok(SectionKind::ImageData) => reader.image_data = cursor.remaining(),
+                Ok(SectionKind::Continuations) => {
+                    reader.num_continuations = cursor.uint(widths.index)? as u32;
+                    reader.continuations = cursor;
+                }
+                Ok(SectionKind::Headings) => {
+                    reader.num_headings = cursor.uint(widths.index)? as u32;
+                    reader.headings = cursor;
+                }
+                Ok(SectionKind::SecondaryRuby) => {
+                    reader.num_secondary_ruby_blocks = cursor.uint(widths.index)? as u32;
+                    reader.secondary_ruby = cursor;
+                }
+                Ok(SectionKind::Spans) => {
+                    reader.num_span_blocks = cursor.uint(widths.index)? as u32;
+                    reader.spans = cursor;
+                }
+                Ok(SectionKind::Emphasis) => {
+                    reader.num_emphasis_blocks = cursor.uint(widths.index)? as u32;
+                    reader.emphasis = cursor;
+                }
+                Ok(SectionKind::Layout) => {
+                    reader.num_layout_blocks = cursor.uint(widths.index)? as u32;
+                    reader.layout = cursor;
+                }
+                Err(_) => {}
+            }
+        }
+
+        reader.header = Some(header);
+
+        Ok(reader)
+    }
+
+    /// legacy reads a file from before the header was added. These files start with the number
+    /// of blocks, followed by the image metadata, the blocks and then the image data.
+    fn legacy(bytes: &'a [u8]) -> Result<Self, ReadError> {
+        let mut reader = Self::empty(bytes);
+        let mut cursor = Cursor::new(bytes);
+
+        reader.num_blocks = cursor.u16()?.into();
+
+        reader.num_images = cursor.u8()?.into();
+        reader.image_table = cursor;
+        let image_table_len = reader
+            .num_images()
+            .checked_mul(reader.widths.image_table_entry_len())
+            .ok_or(ReadError::UnexpectedEnd { offset: cursor.pos })?;
+        cursor.take(image_table_len)?;
+
+        reader.blocks = cursor;
+        for _ in 0..reader.num_blocks {
+            next_block(&mut cursor, reader.widths)?;
+        }
+
+        reader.image_data = cursor.remaining();
+
+        Ok(reader)
+    }
+
+    fn validate(&self) -> Result<(), ReadError> {
+        if let Some(mut cursor) = self.metadata
+            && let Some(cover) = read_metadata(&mut cursor)?.cover_image
+            && cover >= self.num_images
+        {
+            return Err(ReadError::InvalidCover);
+        }
+
+        let mut cursor = self.image_table;
+        for index in 0..self.num_images() {
+            let (offset, len) = (
+                cursor.uint(self.widths.offset)?,
+                cursor.uint(self.widths.offset)?,
+            );
+            if image_range(self.image_data, offset, len).is_none() {
+                return Err(ReadError::InvalidImage { index });
+            }
+        }
+
+        if self.num_image_info != 0 {
+            let mut cursor = self.image_info;
+            if self.num_image_info != self.num_images {
+                return Err(ReadError::InvalidImageInfo);
+            }
+            let image_info_len = self
+                .num_images()
+                .checked_mul(IMAGE_INFO_ENTRY_LEN)
+                .ok_or(ReadError::UnexpectedEnd { offset: cursor.pos })?;
+            cursor.take(image_info_len)?;
+        }
+
+        // text_lens are the lengths of the text blocks, or None for images, for checking the
+        // sections which refer to blocks
+        let mut text_lens = Vec::new();
+        let mut cursor = self.blocks;
+        for block_idx in 0..self.num_blocks() {
+            match next_block(&mut cursor, self.widths)? {
+                BlockRef::Image { index } if index >= self.num_images => {
+                    return Err(ReadError::InvalidImageIndex { block_idx });
+                }
+                BlockRef::Image { .. } => text_lens.push(None),
+                BlockRef::Text(block) => {
+                    if !ruby_fits(block.ruby, block.num_ruby, block.text.len())? {
+                        return Err(ReadError::InvalidRuby { block_idx });
+                    }
+                    text_lens.push(Some(block.text.len()));
+                }
+            }
+        }
+
+        let mut cursor = self.secondary_ruby;
+        let mut previous = None;
+        for index in 0..self.num_secondary_ruby_blocks as usize {
+            let entry = next_secondary_ruby(&mut cursor, self.widths)?;
+            let fits = match text_lens.get(entry.block_idx as usize) {
+                Some(&Some(text_len)) => ruby_fits(entry.ruby, entry.num_ruby, text_len)?,
+                _ => false,
+            };
+            if !fits || previous >= Some(entry.block_idx) {
+                return Err(ReadError::InvalidSecondaryRuby { index });
+            }
+
+            previous = Some(entry.block_idx);
+        }
+
+        validate_span_lists(
+            self.spans,
+            self.num_span_blocks,
+            self.widths,
+            &text_lens,
+            |index| ReadError::InvalidSpans { index },
+        )?;
+        validate_span_lists(
+            self.emphasis,
+            self.num_emphasis_blocks,
+            self.widths,
+            &text_lens,
+            |index| ReadError::InvalidEmphasis { index },
+        )?;
+
+        let mut cursor = self.layout;
+        let mut previous = None;
+        for index in 0..self.num_layout_blocks as usize {
+            let (block_idx, _) = next_layout(&mut cursor, self.widths)?;
+            if !matches!(text_lens.get(block_idx as usize), Some(Some(_)))
+                || previous >= Some(block_idx)
+            {
+                return Err(ReadError::InvalidLayout { index });
+            }
+
+            previous = Some(block_idx);
+        }
+
+        let mut cursor = self.toc;
+        for index in 0..self.num_toc_entries as usize {
+            let entry = next_toc_entry(&mut cursor, self.widths)?;
+            if entry.block_idx >= self.num_blocks {
+                return Err(ReadError::InvalidTocEntry { index });
+            }
+        }
+
+        let mut cursor = self.continuations;
+        let mut previous = None;
+        for index in 0..self.num_continuations as usize {
+            let block_idx = cursor.uint(self.widths.index)?;
+            if !matches!(text_lens.get(block_idx as usize), Some(Some(_)))
+                || previous >= Some(block_idx)
+            {
+                return Err(ReadError::InvalidContinuation { index });
+            }
+
+            previous = Some(block_idx);
+        }
+
+        let mut cursor = self.headings;
+        let mut previous = None;
+        for index in 0..self.num_headings as usize {
+            let heading = next_heading(&mut cursor, self.widths)?;
+            if !matches!(text_lens.get(heading.block_idx as usize), Some(Some(_)))
+                || previous >= Some(heading.block_idx)
+                || !(1..=6).contains(&heading.level)
+            {
+                return Err(ReadError::InvalidHeading { index });
+            }
+
+            previous = Some(heading.block_idx);
+        }
+
+        Ok(())
+    }
+
+    /// header returns the header of the file, or `None` for files from before the header was
+    /// added.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata
+            .and_then(|mut cursor| read_metadata(&mut cursor).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn toc(&self) -> TocEntries<'a> {
+        TocEntries {
+            cursor: self.toc,
+            widths: self.widths,
+            remaining: self.num_toc_entries,
+        }
+    }
+
+    pub fn num_images(&self) -> usize {
+        self.num_images as usize
+    }
+
+    /// cover_image returns the data of the cover image, if the book has one.
+    pub fn cover_image(&self) -> Option<&'a [u8]> {
+        self.image(self.metadata().cover_image? as usize)
+    }
+
+    /// image returns the content of the image at `index`.
+    pub fn image(&self, index: usize) -> Option<&'a [u8]> {
+        let (offset, len) = self.image_table_entry(index)?;
+        image_range(self.image_data, offset, len)
+    }
+
+    /// image_offset returns where the image at `index` starts within the image data.
+    pub fn image_offset(&self, index: usize) -> Option<u64> {
+        self.image_table_entry(index).map(|(offset, _)| offset)
+    }
+
+    /// image_table_entry returns the offset and length of the image at `index`.
+    fn image_table_entry(&self, index: usize) -> Option<(u64, u64)> {
+        if index >= self.num_images() {
+            return None;
+        }
+
+        let mut cursor = self.image_table;
+        cursor
+            .take(index * self.widths.image_table_entry_len())
+            .ok()?;
+        Some((
+            cursor.uint(self.widths.offset).ok()?,
+            cursor.uint(self.widths.offset).ok()?,
+        ))
+    }
+
+    /// image_info returns the format and size of the image at `index`. These are unknown for
+    /// files which were written before they were stored.
+    pub fn image_info(&self, index: usize) -> ImageInfo {
+        if index >= self.num_image_info as usize {
+            return ImageInfo::default();
+        }
+
+        let mut cursor = self.image_info;
+        let read = |cursor: &mut Cursor<'_>| -> Result<ImageInfo, ReadError> {
+            cursor.take(index * IMAGE_INFO_ENTRY_LEN)?;
+            Ok(ImageInfo {
+                format: cursor.u8()?.into(),
+                width: cursor.u32()?,
+                height: cursor.u32()?,
+            })
+        };
+
+        // The reader validated the image information, so this doesn't fail
+        read(&mut cursor).unwrap_or_default()
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        (0..self.num_images()).filter_map(|i| self.image(i))
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks as usize
+    }
+
+    pub fn blocks(&self) -> Blocks<'a> {
+        Blocks {
+            cursor: self.blocks,
+            widths: self.widths,
+            remaining: self.num_blocks,
+            block_idx: 0,
+            continuations: self.continuations,
+            remaining_continuations: self.num_continuations,
+            headings: self.headings,
+            remaining_headings: self.num_headings,
+            secondary_ruby: self.secondary_ruby,
+            remaining_secondary_ruby: self.num_secondary_ruby_blocks,
+            spans: self.spans,
+            remaining_spans: self.num_span_blocks,
+            emphasis: self.emphasis,
+            remaining_emphasis: self.num_emphasis_blocks,
+            layout: self.layout,
+            remaining_layout: self.num_layout_blocks,
+        }
+    }
+
+    /// headings returns the blocks which are headings, without reading the blocks, e.g. to list
+    /// the chapters of the book.
+    pub fn headings(&self) -> Headings<'a> {
+        Headings {
+            cursor: self.headings,
+            widths: self.widths,
+            remaining: self.num_headings,
+        }
+    }
+}
+
+/// IMAGE_INFO_ENTRY_LEN is the size of the format (u8), width (u32) and height (u32) of an image.
+const IMAGE_INFO_ENTRY_LEN: usize = 9;
+
+/// ImageInfo describes an image without having to decode it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    /// width and height are the size of the image in pixels. Both are 0 when the size isn't
+    /// known.
+    pub width: u32,
+    pub height: u32,
+}
+
+fn image_range(image_data: &[u8], offset: u64, len: u64) -> Option<&[u8]> {
+    let start = usize::try_from(offset).ok()?;
+    start
+        .checked_add(usize::try_from(len).ok()?)
+        .and_then(|end| image_data.get(start..end))
+}
+
+fn read_header(bytes: &[u8]) -> Result<Option<Header>, ReadError> {
+    if !bytes.starts_with(&MAGIC) {
+        return Ok(None);
+    }
+
+    let mut cursor = Cursor::new(bytes);
+    cursor.take(MAGIC.len())?;
+
+    let version = cursor.u16()?;
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(ReadError::UnsupportedVersion(version));
+    }
+
+    let flags = cursor.u16()?;
+    let widths = Widths::of(version);
+
+    let num_sections = cursor.u16()?;
+    let mut sections = Vec::with_capacity(usize::from(num_sections));
+    for _ in 0..num_sections {
+        sections.push(Section {
+            kind: cursor.u16()?,
+            offset: cursor.uint(widths.offset)?,
+            length: cursor.uint(widths.offset)?,
+        });
+    }
+
+    Ok(Some(Header {
+        version,
+        flags,
+        sections,
+    }))
+}
+
+fn read_metadata(cursor: &mut Cursor<'_>) -> Result<Metadata, ReadError> {
+    let title = cursor.string()?.to_string();
+    let language = cursor.string()?.to_string();
+    let publisher = cursor.string()?.to_string();
+    let identifier = cursor.string()?.to_string();
+    let date = cursor.string()?.to_string();
+
+    let num_creators = cursor.u8()?;
+    let mut creators = Vec::with_capacity(usize::from(num_creators));
+    for _ in 0..num_creators {
+        creators.push(Creator {
+            name: cursor.string()?.to_string(),
+            role: cursor.string()?.to_string(),
+        });
+    }
+
+    let series = Series {
+        name: cursor.string()?.to_string(),
+        index: cursor.string()?.to_string(),
+    };
+
+    let cover_image = Some(cursor.u32()?).filter(|&index| index != u32::MAX);
+
+    Ok(Metadata {
+        title,
+        creators,
+        language,
+        publisher,
+        identifier,
+        date,
+        series: (!series.name.is_empty()).then_some(series),
+        cover_image,
+    })
+}
+
+/// TocEntryRef is an entry in the table of contents which borrows from the file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TocEntryRef<'a> {
+    pub title: Utf16Str<'a>,
+    /// level is the depth of nesting of the entry, starting at 0 for top-level entries.
+    pub level: u8,
+    /// block_idx is the index of the block that the entry points to.
+    pub block_idx: u32,
+}
+
+impl From<TocEntryRef<'_>> for TocEntry {
+    fn from(entry: TocEntryRef<'_>) -> Self {
+        Self {
+            title: entry.title.to_string(),
+            level: entry.level,
+            block_idx: entry.block_idx,
+        }
+    }
+}
+
+fn next_toc_entry<'a>(
+    cursor: &mut Cursor<'a>,
+    widths: Widths,
+) -> Result<TocEntryRef<'a>, ReadError> {
+    let block_idx = cursor.uint(widths.index)? as u32;
+    let level = cursor.u8()?;
+    let title = cursor.string()?;
+
+    Ok(TocEntryRef {
+        title,
+        level,
+        block_idx,
+    })
+}
+
+pub struct TocEntries<'a> {
+    cursor: Cursor<'a>,
+    widths: Widths,
+    remaining: u32,
+}
+
+impl<'a> Iterator for TocEntries<'a> {
+    type Item = TocEntryRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+
+        // The reader validated the entries, so this doesn't fail
+        next_toc_entry(&mut self.cursor, self.widths).ok()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for TocEntries<'_> {}
+
+/// BlockRef is a block which borrows from the file.
+#[derive(Clone, Copy, Debug)]
+pub enum BlockRef<'a> {
+    Text(TextBlock<'a>),
+    Image { index: u32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TextBlock<'a> {
+    pub text: Utf16Str<'a>,
+    /// flags indicate paragraph-level formatting information.
+    /// Lowest bit is bold.
+    /// Second-lowest bit is large text.
+    /// Third-lowest bit is set when the block continues the last paragraph of the previous block.
+    /// The higher bits are the layout, like the flags of a [`ContentBlock::Text`].
+    pub flags: u8,
+    /// heading_level is the level of a heading, from 1 to 6, or 0 for text which isn't a heading.
+    pub heading_level: u8,
+    /// ruby is positioned at the first ruby of the block.
+    ruby: Cursor<'a>,
+    num_ruby: u8,
+    /// secondary_ruby is positioned at the first secondary ruby of the block.
+    secondary_ruby: Cursor<'a>,
+    num_secondary_ruby: u8,
+    spans: RawSpans<'a>,
+    emphasis: RawSpans<'a>,
+}
+
+impl<'a> TextBlock<'a> {
+    pub fn ruby(&self) -> RubyIter<'a> {
+        RubyIter {
+            cursor: self.ruby,
+            remaining: self.num_ruby,
+        }
+    }
+
+    /// secondary_ruby returns the annotations from `<rtc>`, which are shown on the other side of
+    /// the text from [`ruby`](Self::ruby).
+    pub fn secondary_ruby(&self) -> RubyIter<'a> {
+        RubyIter {
+            cursor: self.secondary_ruby,
+            remaining: self.num_secondary_ruby,
+        }
+    }
+
+    /// spans returns the inline formatting of parts of the text.
+    pub fn spans(&self) -> Spans<'a> {
+        Spans(self.spans)
+    }
+
+    /// emphasis returns the emphasis marks (傍点) beside parts of the text.
+    pub fn emphasis(&self) -> EmphasisMarks<'a> {
+        EmphasisMarks(self.emphasis)
+    }
+}
+
+impl From<BlockRef<'_>> for ContentBlock {
+    fn from(block: BlockRef<'_>) -> Self {
+        match block {
+            BlockRef::Text(block) => Self::Text {
+                text: block.text.units().collect(),
+                ruby: block.ruby().map(Ruby::from).collect(),
+                secondary_ruby: block.secondary_ruby().map(Ruby::from).collect(),
+                spans: block.spans().collect(),
+                emphasis: block.emphasis().collect(),
+                flags: block.flags,
+                heading_level: block.heading_level,
+            },
+            BlockRef::Image { index } => Self::Image { index },
+        }
+    }
+}
+
+fn next_block<'a>(cursor: &mut Cursor<'a>, widths: Widths) -> Result<BlockRef<'a>, ReadError> {
+    let prefix = cursor.u16()?;
+
+    if prefix & (1 << 15) != 0 {
+        let index = if widths.num_images == 1 {
+            // The index is written from a u8
+            u32::from(prefix as u8)
+        } else {
+            cursor.uint(widths.num_images)? as u32
+        };
+
+        return Ok(BlockRef::Image { index });
+    }
+
+    let flags = (prefix >> 13) as u8;
+    let length = usize::from(prefix & !(0b111 << 13));
+
+    // Empty blocks don't have a list of ruby
+    let text = cursor.utf16(length)?;
+    let num_ruby = if length == 0 { 0 } else { cursor.u8()? };
+
+    let ruby = *cursor;
+    for _ in 0..num_ruby {
+        next_ruby(cursor)?;
+    }
+
+    Ok(BlockRef::Text(TextBlock {
+        text,
+        flags,
+        heading_level: 0,
+        ruby,
+        num_ruby,
+        secondary_ruby: ruby,
+        num_secondary_ruby: 0,
+        spans: RawSpans::empty(ruby),
+        emphasis: RawSpans::empty(ruby),
+    }))
+}
+
+/// Blocks iterates over the blocks of a file, in order.
+pub struct Blocks<'a> {
+    cursor: Cursor<'a>,
+    widths: Widths,
+    remaining: u32,
+    /// block_idx is the index of the next block.
+    block_idx: u32,
+    /// continuations is positioned at the index of the next block which continues the previous
+    /// one.
+    continuations: Cursor<'a>,
+    remaining_continuations: u32,
+    /// headings is positioned at the next heading.
+    headings: Cursor<'a>,
+    remaining_headings: u32,
+    /// secondary_ruby is positioned at the next block with secondary ruby.
+    secondary_ruby: Cursor<'a>,
+    remaining_secondary_ruby: u32,
+    /// spans is positioned at the next block with spans.
+    spans: Cursor<'a>,
+    remaining_spans: u32,
+    /// emphasis is positioned at the next block with emphasis marks.
+    emphasis: Cursor<'a>,
+    remaining_emphasis: u32,
+    /// layout is positioned at the next block with a layout.
+    layout: Cursor<'a>,
+    remaining_layout: u32,
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = BlockRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+
+        // The reader validated the blocks, so this doesn't fail
+        let mut block = next_block(&mut self.cursor, self.widths).ok()?;
+
+        let mut continuations = self.continuations;
+        if self.remaining_continuations > 0
+            && continuations.uint(self.widths.index).ok() == Some(self.block_idx.into())
+        {
+            self.continuations = continuations;
+            self.remaining_continuations -= 1;
+
+            if let BlockRef::Text(ref mut block) = block {
+                block.flags |= 1 << 2;
+            }
+        }
+
+        let mut headings = self.headings;
+        if self.remaining_headings > 0
+            && let Ok(heading) = next_heading(&mut headings, self.widths)
+            && heading.block_idx == self.block_idx
+        {
+            self.headings = headings;
+            self.remaining_headings -= 1;
+
+            if let BlockRef::Text(ref mut block) = block {
+                block.heading_level = heading.level;
+            }
+        }
+
+        let mut layout = self.layout;
+        if self.remaining_layout > 0
+            && let Ok((block_idx, flags)) = next_layout(&mut layout, self.widths)
+            && block_idx == self.block_idx
+        {
+            self.layout = layout;
+            self.remaining_layout -= 1;
+
+            if let BlockRef::Text(ref mut block) = block {
+                block.flags |= flags & !0b111;
+            }
+        }
+
+        let mut secondary_ruby = self.secondary_ruby;
+        if self.remaining_secondary_ruby > 0
+            && let Ok(entry) = next_secondary_ruby(&mut secondary_ruby, self.widths)
+            && entry.block_idx == self.block_idx
+        {
+            self.secondary_ruby = secondary_ruby;
+            self.remaining_secondary_ruby -= 1;
+
+            if let BlockRef::Text(ref mut block) = block {
+                block.secondary_ruby = entry.ruby;
+                block.num_secondary_ruby = entry.num_ruby;
+            }
+        }
+
+        if let Some(spans) = next_spans_of(
+            &mut self.spans,
+            &mut self.remaining_spans,
+            self.widths,
+            self.block_idx,
+        ) && let BlockRef::Text(ref mut block) = block
+        {
+            block.spans = spans;
+        }
+
+        if let Some(emphasis) = next_spans_of(
+            &mut self.emphasis,
+            &mut self.remaining_emphasis,
+            self.widths,
+            self.block_idx,
+        ) && let BlockRef::Text(ref mut block) = block
+        {
+            block.emphasis = emphasis;
+        }
+
+        self.block_idx += 1;
+
+        Some(block)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for Blocks<'_> {}
+
+/// HeadingRef is a text block which is a heading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeadingRef {
+    pub block_idx: u32,
+    /// level is the level of the heading, from 1 for `<h1>` to 6 for `<h6>`.
+    pub level: u8,
+}
+
+pub struct Headings<'a> {
+    cursor: Cursor<'a>,
+    widths: Widths,
+    remaining: u32,
+}
+
+impl Iterator for Headings<'_> {
+    type Item = HeadingRef;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+
+        // The reader validated the headings, so this doesn't fail
+        next_heading(&mut self.cursor, self.widths).ok()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for Headings<'_> {}
+
+fn next_heading(cursor: &mut Cursor<'_>, widths: Widths) -> Result<HeadingRef, ReadError> {
+    Ok(HeadingRef {
+        block_idx: cursor.uint(widths.index)? as u32,
+        level: cursor.u8()?,
+    })
+}
+
+/// next_layout returns the index of a text block and its layout flags.
+fn next_layout(cursor: &mut Cursor<'_>, widths: Widths) -> Result<(u32, u8), ReadError> {
+    Ok((cursor.uint(widths.index)? as u32, cursor.u8()?))
+}
+
+/// SecondaryRubyEntry is the secondary ruby of a text block.
+#[derive(Clone, Copy)]
+struct SecondaryRubyEntry<'a> {
+    block_idx: u32,
+    /// ruby is positioned at the first ruby of the block.
+    ruby: Cursor<'a>,
+    num_ruby: u8,
+}
+
+fn next_secondary_ruby<'a>(
+    cursor: &mut Cursor<'a>,
+    widths: Widths,
+) -> Result<SecondaryRubyEntry<'a>, ReadError> {
+    let block_idx = cursor.uint(widths.index)? as u32;
+    let num_ruby = cursor.u8()?;
+    let ruby = *cursor;
+    for _ in 0..num_ruby {
+        next_ruby(cursor)?;
+    }
+
+    Ok(SecondaryRubyEntry {
+        block_idx,
+        ruby,
+        num_ruby,
+    })
+}
+
+/// SPAN_LEN is the size of the start offset (u16), length (u16) and style (u8) of a span.
+const SPAN_LEN: usize = 5;
+
+/// SpanEntry is the spans of a text block, from a section with the layout of the spans.
+#[derive(Clone, Copy)]
+struct SpanEntry<'a> {
+    block_idx: u32,
+    spans: RawSpans<'a>,
+}
+
+fn next_spans<'a>(cursor: &mut Cursor<'a>, widths: Widths) -> Result<SpanEntry<'a>, ReadError> {
+    let block_idx = cursor.uint(widths.index)? as u32;
+    let num_spans = cursor.u16()?;
+    let spans = RawSpans {
+        cursor: *cursor,
+        remaining: num_spans,
+    };
+    cursor.take(usize::from(num_spans) * SPAN_LEN)?;
+
+    Ok(SpanEntry { block_idx, spans })
+}
+
+/// next_spans_of returns the spans of the block at `block_idx` from a section with the layout of
+/// the spans, and advances past them. The spans of the blocks before it have to have been read
+/// already.
+fn next_spans_of<'a>(
+    cursor: &mut Cursor<'a>,
+    remaining: &mut u32,
+    widths: Widths,
+    block_idx: u32,
+) -> Option<RawSpans<'a>> {
+    if *remaining == 0 {
+        return None;
+    }
+
+    let mut next = *cursor;
+    let entry = next_spans(&mut next, widths).ok()?;
+    if entry.block_idx != block_idx {
+        return None;
+    }
+
+    *cursor = next;
+    *remaining -= 1;
+    Some(entry.spans)
+}
+
+/// validate_span_lists checks that the `num_blocks` entries of a section with the layout of the
+/// spans point to text blocks in increasing order, and that their spans are within the text.
+/// `text_lens` are the lengths of the text of each block, which are `None` for images.
+fn validate_span_lists(
+    mut cursor: Cursor<'_>,
+    num_blocks: u32,
+    widths: Widths,
+    text_lens: &[Option<usize>],
+    error: impl Fn(usize) -> ReadError,
+) -> Result<(), ReadError> {
+    let mut previous = None;
+    for index in 0..num_blocks as usize {
+        let SpanEntry {
+            block_idx,
+            mut spans,
+        } = next_spans(&mut cursor, widths)?;
+        let fits = match text_lens.get(block_idx as usize) {
+            Some(&Some(text_len)) => spans.all(|(start_offset, length, _)| {
+                usize::from(start_offset) + usize::from(length) <= text_len
+            }),
+            _ => false,
+        };
+        if !fits || previous >= Some(block_idx) {
+            return Err(error(index));
+        }
+
+        previous = Some(block_idx);
+    }
+
+    Ok(())
+}
+
+/// RawSpans iterates over the start offset, length and style of spans in a section with the
+/// layout of the spans.
+#[derive(Clone, Copy, Debug)]
+struct RawSpans<'a> {
+    cursor: Cursor<'a>,
+    remaining: u16,
+}
+
+impl<'a> RawSpans<'a> {
+    fn empty(cursor: Cursor<'a>) -> Self {
+        Self {
+            cursor,
+            remaining: 0,
+        }
+    }
+}
+
+impl Iterator for RawSpans<'_> {
+    type Item = (u16, u16, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+
+        // The reader validated the spans, so this doesn't fail
+        let mut read = || -> Result<Self::Item, ReadError> {
+            Ok((self.cursor.u16()?, self.cursor.u16()?, self.cursor.u8()?))
+        };
+        read().ok()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (
+            usize::from(self.remaining),
+            Some(usize::from(self.remaining)),
+        )
+    }
+}
+
+/// Spans iterates over the spans of a text block, in order of where they start.
+pub struct Spans<'a>(RawSpans<'a>);
+
+impl Iterator for Spans<'_> {
+    type Item = StyleSpan;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (start_offset, length, style) = self.0.next()?;
+
+        Some(StyleSpan {
+            start_offset,
+            length,
+            style: style.into(),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Spans<'_> {}
+
+/// EmphasisMarks iterates over the emphasis marks of a text block, in order of where they start.
+pub struct EmphasisMarks<'a>(RawSpans<'a>);
+
+impl Iterator for EmphasisMarks<'_> {
+    type Item = Emphasis;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (start_offset, length, style) = self.0.next()?;
+
+        Some(Emphasis {
+            start_offset,
+            length,
+            style: style.into(),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl ExactSizeIterator for EmphasisMarks<'_> {}
+
+/// ruby_fits returns whether each of the `num_ruby` ruby at `cursor` applies to text within the
+/// first `text_len` characters.
+fn ruby_fits(mut cursor: Cursor<'_>, num_ruby: u8, text_len: usize) -> Result<bool, ReadError> {
+    for _ in 0..num_ruby {
+        let r = next_ruby(&mut cursor)?;
+        if usize::from(r.start_offset) + usize::from(r.length) > text_len {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// RubyRef is a reading for some of the text of a block, which borrows from the file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RubyRef<'a> {
+    /// start_offset is the offset into the text of the block where this `reading` starts.
+    pub start_offset: u16,
+    /// length is the number of characters in the block that this reading is associated with.
+    pub length: u8,
+    pub reading: Utf16Str<'a>,
+}
+
+impl From<RubyRef<'_>> for Ruby {
+    fn from(ruby: RubyRef<'_>) -> Self {
+        Self {
+            start_offset: ruby.start_offset,
+            length: ruby.length,
+            reading: ruby.reading.units().collect(),
+        }
+    }
+}
+
+fn next_ruby<'a>(cursor: &mut Cursor<'a>) -> Result<RubyRef<'a>, ReadError> {
+    let start_offset = cursor.u16()?;
+    let length = cursor.u8()?;
+    let reading_len = cursor.u8()?;
+    let reading = cursor.utf16(usize::from(reading_len))?;
+
+    Ok(RubyRef {
+        start_offset,
+        length,
+        reading,
+    })
+}
+
+pub struct RubyIter<'a> {
+    cursor: Cursor<'a>,
+    remaining: u8,
+}
+
+impl<'a> Iterator for RubyIter<'a> {
+    type Item = RubyRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+
+        // The reader validated the ruby, so this doesn't fail
+        next_ruby(&mut self.cursor).ok()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (
+            usize::from(self.remaining),
+            Some(usize::from(self.remaining)),
+        )
+    }
+}
+
+impl ExactSizeIterator for RubyIter<'_> {}
+
+/// Utf16Str is valid UTF-16LE encoded text which borrows from the file.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Utf16Str<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Utf16Str<'a> {
+    /// len returns the number of UTF-16 code units in the string.
+    pub fn len(&self) -> usize {
+        self.bytes.len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn units(&self) -> impl Iterator<Item = u16> + 'a {
+        self.bytes
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+    }
+
+    pub fn chars(&self) -> impl Iterator<Item = char> + 'a {
+        decode_utf16(self.units()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+impl fmt::Display for Utf16Str<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chars().try_for_each(|c| fmt::Write::write_char(f, c))
+    }
+}
+
+impl fmt::Debug for Utf16Str<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+/// Cursor reads values from part of a file, failing instead of reading past the end of that part.
+#[derive(Clone, Copy)]
+struct Cursor<'a> {
+    file: &'a [u8],
+    /// pos is the offset within the file of the next value.
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(file: &'a [u8]) -> Self {
+        Self {
+            file,
+            pos: 0,
+            end: file.len(),
+        }
+    }
+
+    fn empty(file: &'a [u8]) -> Self {
+        Self {
+            file,
+            pos: 0,
+            end: 0,
+        }
+    }
+
+    /// section returns a cursor for `section`, or `None` when it isn't entirely within `file`.
+    fn section(file: &'a [u8], section: &Section) -> Option<Self> {
+        let pos = usize::try_from(section.offset).ok()?;
+        let end = pos.checked_add(usize::try_from(section.length).ok()?)?;
+        if end > file.len() {
+            return None;
+        }
+
+        Some(Self { file, pos, end })
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.file[self.pos..self.end]
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ReadError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.end)
+            .ok_or(ReadError::UnexpectedEnd { offset: self.pos })?;
+
+        let taken = &self.file[self.pos..end];
+        self.pos = end;
+
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ReadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ReadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ReadError> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    /// uint reads a little-endian integer which is `width` bytes wide, up to 8.
+    fn uint(&mut self, width: usize) -> Result<u64, ReadError> {
+        let mut bytes = [0; 8];
+        bytes[..width].copy_from_slice(self.take(width)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// utf16 reads `len` bytes of UTF-16LE encoded text.
+    fn utf16(&mut self, len: usize) -> Result<Utf16Str<'a>, ReadError> {
+        let offset = self.pos;
+        let s = Utf16Str {
+            bytes: self.take(len)?,
+        };
+
+        if !len.is_multiple_of(2) || decode_utf16(s.units()).any(|c| c.is_err()) {
+            return Err(ReadError::InvalidString { offset });
+        }
+
+        Ok(s)
+    }
+
+    /// string reads a string which is prefixed by its length in bytes (u16).
+    fn string(&mut self) -> Result<Utf16Str<'a>, ReadError> {
+        let len = self.u16()?;
+        self.utf16(usize::from(len))
+    }
+}
+
+impl fmt::Debug for Cursor<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cursor")
+            .field("pos", &self.pos)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Book, EmphasisStyle, Image, SpanStyle,
+        format::{COMPACT_VERSION, WIDE_VERSION, write_version},
+    };
+
+    fn sample() -> Vec<u8> {
+        sample_version(COMPACT_VERSION)
+    }
+
+    fn sample_version(version: u16) -> Vec<u8> {
+        let book = Book {
+            toc: vec![TocEntry {
+                title: "一".to_string(),
+                level: 0,
+                block_idx: 1,
+            }],
+            blocks: vec![
+                ContentBlock::Image { index: 0 },
+                ContentBlock::Text {
+                    text: "開発".encode_utf16().collect(),
+                    ruby: Box::new([Ruby {
+                        start_offset: 0,
+                        length: 2,
+                        reading: "かいはつ".encode_utf16().collect(),
+                    }]),
+                    secondary_ruby: Box::new([]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([]),
+                    flags: 0,
+                    heading_level: 0,
+                },
+            ],
+            images: vec![Image {
+                data: Box::new([1, 2, 3]),
+                format: ImageFormat::Gif,
+                width: 3,
+                height: 1,
+            }],
+            ..Default::default()
+        };
+
+        let mut buf = Vec::new();
+        write_version(&book, version, &mut buf).unwrap();
+        buf
+    }
+
+    /// offset_of returns the offset of the first occurrence of `needle` in `haystack`.
+    fn offset_of(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .unwrap()
+    }
+
+    #[test]
+    fn read_blocks() {
+        for version in [COMPACT_VERSION, WIDE_VERSION] {
+            let bytes = sample_version(version);
+
+            let reader = Reader::new(&bytes).unwrap();
+            assert_eq!(reader.header().unwrap().version, version);
+
+            let blocks = reader.blocks().collect::<Vec<_>>();
+            assert_eq!(blocks.len(), 2);
+            assert!(matches!(blocks[0], BlockRef::Image { index: 0 }));
+
+            let BlockRef::Text(block) = blocks[1] else {
+                panic!("image");
+            };
+            assert_eq!(block.text.to_string(), "開発");
+            let ruby = block.ruby().collect::<Vec<_>>();
+            assert_eq!(ruby.len(), 1);
+            assert_eq!(ruby[0].reading.to_string(), "かいはつ");
+
+            assert_eq!(reader.image(0), Some([1, 2, 3].as_slice()));
+            assert_eq!(reader.image(1), None);
+            assert_eq!(
+                reader.image_info(0),
+                ImageInfo {
+                    format: ImageFormat::Gif,
+                    width: 3,
+                    height: 1,
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        for version in [COMPACT_VERSION, WIDE_VERSION] {
+            let bytes = sample_version(version);
+
+            for len in 0..bytes.len() {
+                assert!(Reader::new(&bytes[..len]).is_err(), "{version} {len}");
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let mut bytes = sample();
+
+        for version in [0, FORMAT_VERSION + 1] {
+            bytes[4..6].copy_from_slice(&version.to_le_bytes());
+
+            assert_eq!(
+                Reader::new(&bytes).err(),
+                Some(ReadError::UnsupportedVersion(version)),
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt_files_do_not_panic() {
+        for bytes in [
+            sample_version(COMPACT_VERSION),
+            sample_version(WIDE_VERSION),
+        ] {
+            for i in 0..bytes.len() {
+                for value in [0x00, 0x01, 0x7f, 0x80, 0xff] {
+                    let mut corrupt = bytes.clone();
+                    corrupt[i] = value;
+
+                    if let Ok(reader) = Reader::new(&corrupt) {
+                        for block in reader.blocks() {
+                            if let BlockRef::Text(block) = block {
+                                block.ruby().for_each(drop);
+                            }
+                        }
+                        reader.toc().for_each(drop);
+                        reader.images().for_each(drop);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ruby_outside_of_text() {
+        let mut bytes = sample();
+        // The reading starts after the text "開発"
+        let ruby_offset = offset_of(&bytes, &[0x8b, 0x95, 0x7a, 0x76]) + 4 + 1;
+        bytes[ruby_offset] = 1;
+
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidRuby { block_idx: 1 }),
+        );
+    }
+
+    #[test]
+    fn image_index_outside_of_table() {
+        let mut bytes = sample();
+        let blocks_section = Reader::new(&bytes)
+            .unwrap()
+            .header()
+            .unwrap()
+            .sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Blocks as u16)
+            .unwrap()
+            .offset as usize;
+        // The first block is the image, after the number of blocks
+        bytes[blocks_section + 2] = 1;
+
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidImageIndex { block_idx: 0 }),
+        );
+    }
+
+    #[test]
+    fn continuation_of_image() {
+        let book = Book {
+            blocks: vec![
+                ContentBlock::Image { index: 0 },
+                ContentBlock::Text {
+                    text: "続き".encode_utf16().collect(),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([]),
+                    flags: 1 << 2,
+                    heading_level: 0,
+                },
+            ],
+            images: vec![Image {
+                data: Box::new([1]),
+                format: ImageFormat::Unknown,
+                width: 0,
+                height: 0,
+            }],
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        write_version(&book, COMPACT_VERSION, &mut bytes).unwrap();
+
+        let continuations_start = Reader::new(&bytes)
+            .unwrap()
+            .header()
+            .unwrap()
+            .sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Continuations as u16)
+            .map(|section| section.offset as usize)
+            .unwrap();
+        // The index of the block comes after the number of continuations
+        assert_eq!(bytes[continuations_start + 2], 1);
+        bytes[continuations_start + 2] = 0;
+
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidContinuation { index: 0 }),
+        );
+    }
+
+    #[test]
+    fn invalid_heading_level() {
+        let book = Book {
+            blocks: vec![ContentBlock::Text {
+                text: "章".encode_utf16().collect(),
+                ruby: Box::new([]),
+                secondary_ruby: Box::new([]),
+                spans: Box::new([]),
+                emphasis: Box::new([]),
+                flags: 0,
+                heading_level: 1,
+            }],
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        write_version(&book, COMPACT_VERSION, &mut bytes).unwrap();
+
+        let reader = Reader::new(&bytes).unwrap();
+        let Some(BlockRef::Text(block)) = reader.blocks().next() else {
+            panic!("image");
+        };
+        assert_eq!(block.heading_level, 1);
+
+        let headings_end = reader
+            .header()
+            .unwrap()
+            .sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Headings as u16)
+            .map(|section| (section.offset + section.length) as usize)
+            .unwrap();
+        // The level is the last field of the heading
+        bytes[headings_end - 1] = 7;
+
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidHeading { index: 0 }),
+        );
+    }
+
+    #[test]
+    fn heading_of_image() {
+        let book = Book {
+            blocks: vec![
+                ContentBlock::Image { index: 0 },
+                ContentBlock::Text {
+                    text: "章".encode_utf16().collect(),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([]),
+                    flags: 0,
+                    heading_level: 1,
+                },
+            ],
+            images: vec![Image {
+                data: Box::new([1]),
+                format: ImageFormat::Unknown,
+                width: 0,
+                height: 0,
+            }],
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        write_version(&book, COMPACT_VERSION, &mut bytes).unwrap();
+
+        let headings_start = Reader::new(&bytes)
+            .unwrap()
+            .header()
+            .unwrap()
+            .sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Headings as u16)
+            .map(|section| section.offset as usize)
+            .unwrap();
+        // The index of the block comes after the number of headings
+        assert_eq!(bytes[headings_start + 2..headings_start + 5], [1, 0, 1]);
+        bytes[headings_start + 2] = 0;
+
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidHeading { index: 0 }),
+        );
+    }
+
+    #[test]
+    fn spans_outside_of_text() {
+        let book = Book {
+            blocks: vec![ContentBlock::Text {
+                text: "強調".encode_utf16().collect(),
+                ruby: Box::new([]),
+                secondary_ruby: Box::new([]),
+                spans: Box::new([StyleSpan {
+                    start_offset: 1,
+                    length: 1,
+                    style: SpanStyle::Bold,
+                }]),
+                emphasis: Box::new([]),
+                flags: 0,
+                heading_level: 0,
+            }],
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        write_version(&book, COMPACT_VERSION, &mut bytes).unwrap();
+        let spans_end = Reader::new(&bytes)
+            .unwrap()
+            .header()
+            .unwrap()
+            .sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Spans as u16)
+            .map(|section| (section.offset + section.length) as usize)
+            .unwrap();
+
+        // Styles from newer versions are read as unknown
+        bytes[spans_end - 1] = 0xff;
+        let reader = Reader::new(&bytes).unwrap();
+        let Some(BlockRef::Text(block)) = reader.blocks().next() else {
+            panic!("image");
+        };
+        assert_eq!(
+            block.spans().collect::<Vec<_>>(),
+            [StyleSpan {
+                start_offset: 1,
+                length: 1,
+                style: SpanStyle::Unknown,
+            }],
+        );
+
+        // The length comes before the style
+        bytes[spans_end - 3] = 2;
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidSpans { index: 0 }),
+        );
+    }
+
+    #[test]
+    fn emphasis_outside_of_text() {
+        let book = Book {
+            blocks: vec![
+                ContentBlock::Text {
+                    text: "強調".encode_utf16().collect(),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([]),
+                    spans: Box::new([StyleSpan {
+                        start_offset: 0,
+                        length: 2,
+                        style: SpanStyle::Bold,
+                    }]),
+                    emphasis: Box::new([]),
+                    flags: 0,
+                    heading_level: 0,
+                },
+                ContentBlock::Text {
+                    text: "傍点".encode_utf16().collect(),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([Emphasis {
+                        start_offset: 0,
+                        length: 2,
+                        style: EmphasisStyle::FilledSesame,
+                    }]),
+                    flags: 0,
+                    heading_level: 0,
+                },
+            ],
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        write_version(&book, COMPACT_VERSION, &mut bytes).unwrap();
+        let reader = Reader::new(&bytes).unwrap();
+        let blocks = reader
+            .blocks()
+            .map(|block| match block {
+                BlockRef::Text(block) => (block.spans().len(), block.emphasis().collect()),
+                BlockRef::Image { .. } => panic!("image"),
+            })
+            .collect::<Vec<(usize, Vec<Emphasis>)>>();
+        assert_eq!(
+            blocks,
+            [
+                (1, Vec::new()),
+                (
+                    0,
+                    vec![Emphasis {
+                        start_offset: 0,
+                        length: 2,
+                        style: EmphasisStyle::FilledSesame,
+                    }],
+                ),
+            ],
+        );
+
+        let emphasis_start = reader
+            .header()
+            .unwrap()
+            .sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Emphasis as u16)
+            .map(|section| section.offset as usize)
+            .unwrap();
+
+        // The index of the block comes after the number of blocks
+        bytes[emphasis_start + 2] = 2;
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidEmphasis { index: 0 }),
+        );
+    }
+
+    #[test]
+    fn layout_of_image() {
+        let book = Book {
+            blocks: vec![
+                ContentBlock::Image { index: 0 },
+                ContentBlock::Text {
+                    text: "中央".encode_utf16().collect(),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([]),
+                    flags: 1 << 7 | 1 << 3 | 1,
+                    heading_level: 0,
+                },
+            ],
+            images: vec![Image {
+                data: Box::new([1]),
+                format: ImageFormat::Unknown,
+                width: 0,
+                height: 0,
+            }],
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        write_version(&book, COMPACT_VERSION, &mut bytes).unwrap();
+
+        let reader = Reader::new(&bytes).unwrap();
+        let Some(BlockRef::Text(block)) = reader.blocks().nth(1) else {
+            panic!("image");
+        };
+        assert_eq!(block.flags, 1 << 7 | 1 << 3 | 1);
+
+        let layout_start = reader
+            .header()
+            .unwrap()
+            .sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Layout as u16)
+            .map(|section| section.offset as usize)
+            .unwrap();
+
+        // The index of the block comes after the number of blocks
+        assert_eq!(
+            bytes[layout_start + 2..layout_start + 5],
+            [1, 0, 1 << 7 | 1 << 3]
+        );
+        bytes[layout_start + 2] = 0;
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidLayout { index: 0 }),
+        );
+    }
+
+    #[test]
+    fn secondary_ruby_outside_of_text() {
+        let book = Book {
+            blocks: vec![
+                ContentBlock::Image { index: 0 },
+                ContentBlock::Text {
+                    text: "東京".encode_utf16().collect(),
+                    ruby: Box::new([]),
+                    secondary_ruby: Box::new([Ruby {
+                        start_offset: 0,
+                        length: 2,
+                        reading: "Tokyo".encode_utf16().collect(),
+                    }]),
+                    spans: Box::new([]),
+                    emphasis: Box::new([]),
+                    flags: 0,
+                    heading_level: 0,
+                },
+            ],
+            images: vec![Image {
+                data: Box::new([1]),
+                format: ImageFormat::Unknown,
+                width: 0,
+                height: 0,
+            }],
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        write_version(&book, COMPACT_VERSION, &mut bytes).unwrap();
+
+        let reader = Reader::new(&bytes).unwrap();
+        let Some(BlockRef::Text(block)) = reader.blocks().nth(1) else {
+            panic!("image");
+        };
+        assert_eq!(block.ruby().len(), 0);
+        let secondary_ruby = block.secondary_ruby().collect::<Vec<_>>();
+        assert_eq!(secondary_ruby.len(), 1);
+        assert_eq!(secondary_ruby[0].reading.to_string(), "Tokyo");
+
+        let ruby_offset = offset_of(
+            &bytes,
+            &"Tokyo"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>(),
+        );
+        // The start offset comes before the length of the base and of the reading
+        bytes[ruby_offset - 4] = 1;
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidSecondaryRuby { index: 0 }),
+        );
+
+        // Secondary ruby for an image
+        bytes[ruby_offset - 4] = 0;
+        let index_offset = ruby_offset - 4 - 1 - 2;
+        assert_eq!(bytes[index_offset..index_offset + 2], [1, 0]);
+        bytes[index_offset] = 0;
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidSecondaryRuby { index: 0 }),
+        );
+    }
+
+    #[test]
+    fn cover_outside_of_image_table() {
+        let mut bytes = sample();
+        let metadata_end = Reader::new(&bytes)
+            .unwrap()
+            .header()
+            .unwrap()
+            .sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Metadata as u16)
+            .map(|section| (section.offset + section.length) as usize)
+            .unwrap();
+        // The cover is the last field of the metadata
+        bytes[metadata_end - 4..metadata_end].copy_from_slice(&1u32.to_le_bytes());
+
+        assert_eq!(Reader::new(&bytes).err(), Some(ReadError::InvalidCover));
+    }
+
+    #[test]
+    fn unpaired_surrogate() {
+        let mut bytes = sample();
+        let title_offset = offset_of(&bytes, &"一".encode_utf16().next().unwrap().to_le_bytes());
+        bytes[title_offset..title_offset + 2].copy_from_slice(&0xd800u16.to_le_bytes());
+
+        assert_eq!(
+            Reader::new(&bytes).err(),
+            Some(ReadError::InvalidString {
+                offset: title_offset,
+            }),
+        );
+    }
+}
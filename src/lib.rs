@@ -0,0 +1,238 @@
+//! rnb converts EPUB books into a format which is quick to load and lay out on a phone, and reads
+//! the converted files back.
+//!
+//! [`epub::convert`] creates a [`Book`] from an EPUB, [`format::write`] stores it in the `.rnb`
+//! format, and [`format::read`] loads it again.
+
+pub mod epub;
+pub mod format;
+
+/// Book is the content of a book, as it's stored in the `.rnb` format.
+#[derive(Debug, Default, PartialEq)]
+pub struct Book {
+    pub metadata: Metadata,
+    pub toc: Vec<TocEntry>,
+    pub blocks: Vec<ContentBlock>,
+    pub images: Vec<Image>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ContentBlock {
+    Text {
+        /// text is UTF-16 encoded, with paragraphs separated by newlines.
+        text: Box<[u16]>,
+        ruby: Box<[Ruby]>,
+        /// secondary_ruby are the annotations from `<rtc>` (double-sided ruby), which are shown
+        /// on the other side of the text from `ruby`.
+        secondary_ruby: Box<[Ruby]>,
+        /// spans are the inline formatting of parts of the text, in order of where they start.
+        spans: Box<[StyleSpan]>,
+        /// emphasis are the emphasis marks (傍点) beside parts of the text, in order of where they
+        /// start.
+        emphasis: Box<[Emphasis]>,
+        /// flags indicate paragraph-level formatting information.
+        /// Lowest bit is bold.
+        /// Second-lowest bit is large text.
+        /// Third-lowest bit is set when the block continues the last paragraph of the previous
+        /// block, which happens when a paragraph is too long for a single block. There's no gap
+        /// between the paragraph and its continuation.
+        /// The higher bits describe the layout of each paragraph of the block:
+        /// Fourth-lowest bit is centred text, and fifth-lowest bit is text aligned to the end of
+        /// the line.
+        /// Sixth-lowest bit is an indented first line, and seventh-lowest bit is a hanging indent.
+        /// Highest bit is vertical text.
+        flags: u8,
+        /// heading_level is the level of a heading, from 1 for `<h1>` to 6 for `<h6>`, or 0 for
+        /// text which isn't a heading. A heading is always a block of its own.
+        heading_level: u8,
+    },
+    Image {
+        /// index is the index of the image in [`Book::images`].
+        index: u32,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Ruby {
+    /// startOffset is the offset into the text of the paragraph where this
+    /// `reading` starts.
+    pub start_offset: u16,
+    /// length is the number of characters in the paragraph that this reading is
+    /// associated with.
+    pub length: u8,
+    /// reading is the furigana associated with some text within a paragraph.
+    pub reading: Box<[u16]>,
+}
+
+/// StyleSpan is formatting which applies to some of the text of a block, e.g. from `<b>` or
+/// `<em>`. Spans can overlap, e.g. for text which is both bold and italic.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StyleSpan {
+    /// start_offset is the offset into the text of the block where the span starts.
+    pub start_offset: u16,
+    /// length is the number of UTF-16 code units in the block that the span applies to.
+    pub length: u16,
+    pub style: SpanStyle,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(u8)]
+pub enum SpanStyle {
+    /// Unknown is for styles in files which were written by a newer version.
+    #[default]
+    Unknown = 0,
+    Bold = 1,
+    Italic = 2,
+    Larger = 3,
+    Smaller = 4,
+    /// Upright is text which is set horizontally within vertical text (縦中横), e.g. numbers.
+    Upright = 5,
+}
+
+impl From<u8> for SpanStyle {
+    /// from converts a style which was stored as a `u8`. Styles which this version doesn't know
+    /// about are [`SpanStyle::Unknown`].
+    fn from(style: u8) -> Self {
+        match style {
+            1 => Self::Bold,
+            2 => Self::Italic,
+            3 => Self::Larger,
+            4 => Self::Smaller,
+            5 => Self::Upright,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Emphasis is a mark (傍点) beside each character of some of the text of a block, like ruby.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Emphasis {
+    /// start_offset is the offset into the text of the block where the marks start.
+    pub start_offset: u16,
+    /// length is the number of UTF-16 code units in the block that have marks.
+    pub length: u16,
+    pub style: EmphasisStyle,
+}
+
+/// EmphasisStyle is the shape of emphasis marks, which are the same as the values of the CSS
+/// `text-emphasis-style` property. Filled sesame marks (﹅) are the most common in Japanese text.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(u8)]
+pub enum EmphasisStyle {
+    /// Unknown is for styles in files which were written by a newer version.
+    #[default]
+    Unknown = 0,
+    FilledSesame = 1,
+    OpenSesame = 2,
+    FilledDot = 3,
+    OpenDot = 4,
+    FilledCircle = 5,
+    OpenCircle = 6,
+    FilledDoubleCircle = 7,
+    OpenDoubleCircle = 8,
+    FilledTriangle = 9,
+    OpenTriangle = 10,
+}
+
+impl From<u8> for EmphasisStyle {
+    /// from converts a style which was stored as a `u8`. Styles which this version doesn't know
+    /// about are [`EmphasisStyle::Unknown`].
+    fn from(style: u8) -> Self {
+        match style {
+            1 => Self::FilledSesame,
+            2 => Self::OpenSesame,
+            3 => Self::FilledDot,
+            4 => Self::OpenDot,
+            5 => Self::FilledCircle,
+            6 => Self::OpenCircle,
+            7 => Self::FilledDoubleCircle,
+            8 => Self::OpenDoubleCircle,
+            9 => Self::FilledTriangle,
+            10 => Self::OpenTriangle,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Image {
+    /// data is the content of the image file, e.g. a JPEG.
+    pub data: Box<[u8]>,
+    pub format: ImageFormat,
+    /// width and height are the size of the image in pixels. Both are 0 when the size isn't
+    /// known, e.g. for SVG images.
+    pub width: u32,
+    pub height: u32,
+}
+
+/// ImageFormat is the encoding of an image's data.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(u8)]
+pub enum ImageFormat {
+    /// Unknown is for images whose format couldn't be detected, and for images in files which
+    /// were written before formats were stored.
+    #[default]
+    Unknown = 0,
+    Jpeg = 1,
+    Png = 2,
+    Gif = 3,
+    WebP = 4,
+    Svg = 5,
+    Avif = 6,
+}
+
+impl From<u8> for ImageFormat {
+    /// from converts a format which was stored as a `u8`. Formats which this version doesn't
+    /// know about are [`ImageFormat::Unknown`].
+    fn from(format: u8) -> Self {
+        match format {
+            1 => Self::Jpeg,
+            2 => Self::Png,
+            3 => Self::Gif,
+            4 => Self::WebP,
+            5 => Self::Svg,
+            6 => Self::Avif,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// TocEntry is an entry in the book's table of contents.
+#[derive(Debug, PartialEq)]
+pub struct TocEntry {
+    pub title: String,
+    /// level is the depth of nesting of the entry, starting at 0 for top-level entries.
+    pub level: u8,
+    /// block_idx is the index of the block that the entry points to.
+    pub block_idx: u32,
+}
+
+/// Metadata is information about the book, mostly from the `<metadata>` of the package document.
+/// Fields which aren't present in the book are empty.
+#[derive(Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: String,
+    pub creators: Vec<Creator>,
+    pub language: String,
+    pub publisher: String,
+    pub identifier: String,
+    pub date: String,
+    pub series: Option<Series>,
+    /// cover_image is the index in [`Book::images`] of the cover of the book.
+    pub cover_image: Option<u32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Creator {
+    pub name: String,
+    /// role is a MARC relator code, e.g. `aut` for the author or `ill` for the illustrator.
+    pub role: String,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Series {
+    pub name: String,
+    /// index is the position of the book within the series. This isn't necessarily an integer
+    /// (e.g. 2.5).
+    pub index: String,
+}